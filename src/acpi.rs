@@ -0,0 +1,222 @@
+use crate::memory::phys_to_virt;
+use crate::println_info;
+use spin::Once;
+use x86_64::PhysAddr;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+// Reads a `T` from physical memory through the complete physical memory mapping.
+pub(crate) unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    phys_to_virt(addr).as_ptr::<T>().read_unaligned()
+}
+
+fn checksum_ok(addr: PhysAddr, len: u64) -> bool {
+    let sum = (0..len).fold(0u8, |sum, i| {
+        sum.wrapping_add(unsafe { read::<u8>(addr + i) })
+    });
+    sum == 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rsdp {
+    pub address: PhysAddr,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    // The XSDT on ACPI 2.0 and later, the RSDT before.
+    pub root_table: PhysAddr,
+}
+
+impl Rsdp {
+    fn parse(addr: PhysAddr) -> Option<Rsdp> {
+        if unsafe { read::<[u8; 8]>(addr) } != *b"RSD PTR " || !checksum_ok(addr, 20) {
+            return None;
+        }
+        let revision: u8 = unsafe { read(addr + 15u64) };
+        let root_table = if revision >= 2 {
+            if !checksum_ok(addr, u64::from(unsafe { read::<u32>(addr + 20u64) })) {
+                return None;
+            }
+            PhysAddr::new(unsafe { read(addr + 24u64) })
+        } else {
+            PhysAddr::new(u64::from(unsafe { read::<u32>(addr + 16u64) }))
+        };
+        Some(Rsdp {
+            address: addr,
+            revision,
+            oem_id: unsafe { read(addr + 9u64) },
+            root_table,
+        })
+    }
+
+    pub fn is_extended(&self) -> bool {
+        self.revision >= 2
+    }
+}
+
+static RSDP: Once<Option<Rsdp>> = Once::new();
+
+// Looks for the RSDP in the first KiB of the EBDA and in the BIOS area.
+pub fn rsdp() -> Option<Rsdp> {
+    *RSDP.call_once(|| {
+        let ebda = u64::from(unsafe { read::<u16>(PhysAddr::new(0x40e)) }) << 4;
+        let ebda_area = (ebda..ebda + 1024).step_by(16).filter(|_| ebda != 0);
+        let bios_area = (0xe0000..0x100000).step_by(16);
+        ebda_area
+            .chain(bios_area)
+            .find_map(|addr| Rsdp::parse(PhysAddr::new(addr)))
+    })
+}
+
+// The header every system description table starts with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdtHeader {
+    pub address: PhysAddr,
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+}
+
+pub const SDT_HEADER_SIZE: u64 = 36;
+
+impl SdtHeader {
+    pub fn read(addr: PhysAddr) -> SdtHeader {
+        unsafe {
+            SdtHeader {
+                address: addr,
+                signature: read(addr),
+                length: read(addr + 4u64),
+                revision: read(addr + 8u64),
+                oem_id: read(addr + 10u64),
+                oem_table_id: read(addr + 16u64),
+            }
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.length as u64 >= SDT_HEADER_SIZE && checksum_ok(self.address, u64::from(self.length))
+    }
+
+    pub fn signature(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+}
+
+// The valid tables listed in the XSDT or RSDT.
+pub fn tables() -> impl Iterator<Item = SdtHeader> {
+    let root = rsdp().map(|rsdp| (SdtHeader::read(rsdp.root_table), rsdp.is_extended()));
+    let (root, entry_size) = match root {
+        Some((root, extended)) if root.is_valid() => (Some(root), if extended { 8 } else { 4 }),
+        _ => (None, 4),
+    };
+    let count = root.map_or(0, |root| {
+        (u64::from(root.length) - SDT_HEADER_SIZE) / entry_size
+    });
+    (0..count)
+        .map(move |i| {
+            let entry = root.unwrap().address + SDT_HEADER_SIZE + i * entry_size;
+            PhysAddr::new(match entry_size {
+                8 => unsafe { read::<u64>(entry) },
+                _ => u64::from(unsafe { read::<u32>(entry) }),
+            })
+        })
+        .map(SdtHeader::read)
+        .filter(SdtHeader::is_valid)
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<SdtHeader> {
+    tables().find(|table| table.signature == *signature)
+}
+
+// Register location in the format ACPI uses for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    pub(crate) fn read(addr: PhysAddr) -> GenericAddress {
+        let address_space = match unsafe { read::<u8>(addr) } {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        unsafe {
+            GenericAddress {
+                address_space,
+                bit_width: read(addr + 1u64),
+                bit_offset: read(addr + 2u64),
+                access_size: read(addr + 3u64),
+                address: read(addr + 4u64),
+            }
+        }
+    }
+}
+
+// Prints what the firmware tables describe.
+pub fn print_summary() {
+    let rsdp = match rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            println_info!("No ACPI tables found.");
+            return;
+        }
+    };
+    println_info!(
+        "ACPI revision {} from {} at {:#x}.",
+        rsdp.revision,
+        core::str::from_utf8(&rsdp.oem_id).unwrap_or("?"),
+        rsdp.address.as_u64()
+    );
+    if let Some(madt) = madt::Madt::find() {
+        println_info!(
+            "MADT: {} CPUs, {} I/O APICs, {} interrupt overrides.",
+            madt.cpus().filter(|cpu| cpu.enabled).count(),
+            madt.io_apics.iter().flatten().count(),
+            madt.overrides.iter().flatten().count()
+        );
+    }
+    if let Some(fadt) = fadt::Fadt::find() {
+        println_info!(
+            "FADT: SCI on IRQ {}, PM1a control at {:#x}, PM timer at {:#x}.",
+            fadt.sci_interrupt,
+            fadt.pm1a_control_block,
+            fadt.pm_timer_block
+        );
+    }
+    if let Some(hpet) = hpet::Hpet::find() {
+        println_info!(
+            "HPET: {} comparators at {:#x}.",
+            hpet.comparator_count,
+            hpet.address.address
+        );
+    }
+    if let Some(mcfg) = mcfg::Mcfg::find() {
+        for segment in mcfg.segments() {
+            println_info!(
+                "MCFG: PCI segment {} buses {}-{} at {:#x}.",
+                segment.segment,
+                segment.start_bus,
+                segment.end_bus,
+                segment.base.as_u64()
+            );
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use super::{find_table, read, GenericAddress};
+use x86_64::PhysAddr;
+
+// The reset register fields are valid.
+const RESET_REG_SUP: u32 = 1 << 10;
+// The PM timer is 32 bits wide instead of 24.
+const TMR_VAL_EXT: u32 = 1 << 8;
+
+// Fixed ACPI Description Table, the fixed hardware power management registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    // Port that `acpi_enable` is written to, zero if ACPI is always enabled.
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub pm_timer_32bit: bool,
+    // CMOS register of the century, zero if there is none.
+    pub century: u8,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub fn find() -> Option<Fadt> {
+        let header = find_table(b"FACP")?;
+        let table = header.address;
+        let len = u64::from(header.length);
+        let field = |offset: u64| table + offset;
+        let flags: u32 = if len >= 116 {
+            unsafe { read(field(112)) }
+        } else {
+            0
+        };
+        // ACPI 2.0 added a 64 bit DSDT address.
+        let x_dsdt: u64 = if len >= 148 {
+            unsafe { read(field(140)) }
+        } else {
+            0
+        };
+        let dsdt = match x_dsdt {
+            0 => u64::from(unsafe { read::<u32>(field(40)) }),
+            x_dsdt => x_dsdt,
+        };
+        let reset_register = if flags & RESET_REG_SUP != 0 && len >= 129 {
+            Some(GenericAddress::read(field(116)))
+        } else {
+            None
+        };
+        unsafe {
+            Some(Fadt {
+                dsdt: PhysAddr::new(dsdt),
+                sci_interrupt: read(field(46)),
+                smi_command_port: read(field(48)),
+                acpi_enable: read(field(52)),
+                acpi_disable: read(field(53)),
+                pm1a_event_block: read(field(56)),
+                pm1b_event_block: read(field(60)),
+                pm1a_control_block: read(field(64)),
+                pm1b_control_block: read(field(68)),
+                pm_timer_block: read(field(76)),
+                pm_timer_32bit: flags & TMR_VAL_EXT != 0,
+                century: read(field(108)),
+                reset_register,
+                reset_value: if reset_register.is_some() {
+                    read(field(128))
+                } else {
+                    0
+                },
+            })
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use super::{find_table, read, GenericAddress};
+
+// HPET description table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparator_count: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    // The registers, always in system memory.
+    pub address: GenericAddress,
+    pub number: u8,
+    // Minimum number of ticks between periodic interrupts without losing any.
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub fn find() -> Option<Hpet> {
+        let table = find_table(b"HPET")?.address;
+        let block_id: u32 = unsafe { read(table + 36u64) };
+        unsafe {
+            Some(Hpet {
+                hardware_revision: block_id as u8,
+                comparator_count: ((block_id >> 8) & 0x1f) as u8 + 1,
+                counter_64bit: block_id & (1 << 13) != 0,
+                legacy_replacement: block_id & (1 << 15) != 0,
+                pci_vendor_id: (block_id >> 16) as u16,
+                address: GenericAddress::read(table + 40u64),
+                number: read(table + 52u64),
+                minimum_tick: read(table + 53u64),
+            })
+        }
+    }
+}
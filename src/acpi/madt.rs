@@ -0,0 +1,137 @@
+use super::{find_table, read, SDT_HEADER_SIZE};
+use x86_64::PhysAddr;
+
+const MAX_CPUS: usize = 64;
+const MAX_IO_APICS: usize = 8;
+const MAX_OVERRIDES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    // Disabled CPUs can't be started.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    // First global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+// An ISA IRQ that isn't connected to the I/O APIC pin with the same number,
+// or whose polarity or trigger mode differ from the ISA defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+// Multiple APIC Description Table.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    // Whether 8259 PICs are installed as well.
+    pub has_pics: bool,
+    pub cpus: [Option<Cpu>; MAX_CPUS],
+    pub io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    pub overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+fn push<T>(slots: &mut [Option<T>], value: T) {
+    if let Some(slot) = slots.iter_mut().find(|s| s.is_none()) {
+        *slot = Some(value);
+    }
+}
+
+impl Madt {
+    pub fn find() -> Option<Madt> {
+        let header = find_table(b"APIC")?;
+        let table = header.address;
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(u64::from(unsafe {
+                read::<u32>(table + SDT_HEADER_SIZE)
+            })),
+            has_pics: unsafe { read::<u32>(table + 40u64) } & 1 != 0,
+            cpus: [None; MAX_CPUS],
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; MAX_OVERRIDES],
+        };
+        let len = u64::from(header.length);
+        let mut offset = 44;
+        while offset + 2 <= len {
+            let entry = table + offset;
+            let (kind, entry_len) = unsafe { (read::<u8>(entry), read::<u8>(entry + 1u64)) };
+            if entry_len < 2 {
+                break;
+            }
+            match kind {
+                0 => {
+                    let flags: u32 = unsafe { read(entry + 4u64) };
+                    let cpu = Cpu {
+                        processor_id: unsafe { read(entry + 2u64) },
+                        apic_id: unsafe { read(entry + 3u64) },
+                        enabled: flags & 1 != 0,
+                    };
+                    push(&mut madt.cpus, cpu);
+                }
+                1 => {
+                    let io_apic = IoApicInfo {
+                        id: unsafe { read(entry + 2u64) },
+                        address: PhysAddr::new(u64::from(unsafe { read::<u32>(entry + 4u64) })),
+                        gsi_base: unsafe { read(entry + 8u64) },
+                    };
+                    push(&mut madt.io_apics, io_apic);
+                }
+                2 => {
+                    let flags: u16 = unsafe { read(entry + 8u64) };
+                    let irq_override = InterruptOverride {
+                        irq: unsafe { read(entry + 3u64) },
+                        gsi: unsafe { read(entry + 4u64) },
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    };
+                    push(&mut madt.overrides, irq_override);
+                }
+                5 => {
+                    madt.local_apic_address = PhysAddr::new(unsafe { read(entry + 4u64) });
+                }
+                _ => {}
+            }
+            offset += u64::from(entry_len);
+        }
+        Some(madt)
+    }
+
+    pub fn cpus(&self) -> impl Iterator<Item = &Cpu> {
+        self.cpus.iter().flatten()
+    }
+
+    // The global system interrupt an ISA IRQ is connected to.
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .flatten()
+            .find(|o| o.irq == irq)
+            .copied()
+            .unwrap_or(InterruptOverride {
+                irq,
+                gsi: u32::from(irq),
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+
+    pub fn io_apic_for(&self, gsi: u32) -> Option<IoApicInfo> {
+        self.io_apics
+            .iter()
+            .flatten()
+            .filter(|io_apic| io_apic.gsi_base <= gsi)
+            .max_by_key(|io_apic| io_apic.gsi_base)
+            .copied()
+    }
+}
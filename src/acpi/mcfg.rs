@@ -0,0 +1,56 @@
+use super::{find_table, read, SdtHeader, SDT_HEADER_SIZE};
+use x86_64::PhysAddr;
+
+// PCI Express configuration space of one PCI segment group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciSegment {
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl PciSegment {
+    // The configuration space of a function.
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = u64::from(bus - self.start_bus) << 20
+            | u64::from(device) << 15
+            | u64::from(function) << 12;
+        Some(self.base + offset)
+    }
+}
+
+// PCI Express memory mapped configuration table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mcfg {
+    header: SdtHeader,
+}
+
+const ENTRY_SIZE: u64 = 16;
+
+impl Mcfg {
+    pub fn find() -> Option<Mcfg> {
+        find_table(b"MCFG").map(|header| Mcfg { header })
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = PciSegment> {
+        // The entries follow 8 reserved bytes.
+        let first = self.header.address + SDT_HEADER_SIZE + 8u64;
+        let count =
+            (u64::from(self.header.length).saturating_sub(SDT_HEADER_SIZE + 8)) / ENTRY_SIZE;
+        (0..count).map(move |i| {
+            let entry = first + i * ENTRY_SIZE;
+            unsafe {
+                PciSegment {
+                    base: PhysAddr::new(read(entry)),
+                    segment: read(entry + 8u64),
+                    start_bus: read(entry + 10u64),
+                    end_bus: read(entry + 11u64),
+                }
+            }
+        })
+    }
+}
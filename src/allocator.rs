@@ -1,41 +1,334 @@
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::{iter::Map, ptr::null_mut};
+use core::ptr::null_mut;
+#[cfg(feature = "fixed_size_block")]
+use fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(not(feature = "fixed_size_block"))]
 use linked_list_allocator::LockedHeap;
+use tracking::{HeapStats, Tracking};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
     },
     VirtAddr,
 };
 
 pub mod fixed_size_block;
+pub mod slab;
+pub mod tracking;
 
 pub const HEAP_START: usize = 0x4444_4444_0000;
+// Size of the heap mapped by `init_heap`.
 pub const HEAP_SIZE: usize = 100 * 1024;
+// The heap grows on demand up to this size.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+// Minimum number of bytes mapped when the heap grows.
+pub const HEAP_GROW_STEP: usize = 64 * 1024;
+// Free memory at the heap end is given back once there is at least this much.
+pub const HEAP_SHRINK_THRESHOLD: usize = 256 * 1024;
+// Number of times the heap can shrink without growing in between.
+const MAX_RELEASED: usize = 8;
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
 ) -> Result<(), MapToError<Size4KiB>> {
+    use crate::memory::vmm;
+
+    // Keep other regions out of the range the heap can grow into.
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    vmm::reserve_at("heap", heap_start, HEAP_MAX_SIZE as u64)
+        .expect("heap address range is already in use");
+    let mapped = map_heap_pages(HEAP_START, HEAP_SIZE, mapper, frame_allocator);
+    if mapped != HEAP_SIZE {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+    unsafe {
+        ALLOCATOR.inner().init(HEAP_START, HEAP_SIZE);
+    }
+    Ok(())
+}
+
+// Maps the pages of `start..start + size` to fresh frames.
+// Returns the number of bytes mapped, which is less than `size` when mapping failed.
+fn map_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> usize {
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
+    let mut mapped = 0;
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => break,
+        };
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | crate::memory::protect::no_execute();
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                break;
+            }
+        }
+        mapped += page.size() as usize;
     }
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    mapped.min(size)
+}
+
+// Unmaps the pages of `start..start + size` and frees their frames.
+fn unmap_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start as u64));
+    let end_page = Page::containing_address(VirtAddr::new((start + size) as u64));
+    for page in Page::range(start_page, end_page) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
     }
-    Ok(())
 }
+
+// Current size of the mapped heap in bytes.
+pub fn heap_size() -> usize {
+    ALLOCATOR.inner().state.lock().mapped()
+}
+
+// Gives free memory at the end of the heap back to the frame allocator.
+// Returns the number of bytes released. Also happens when large blocks are freed.
+pub fn shrink_heap() -> usize {
+    ALLOCATOR.inner().shrink()
+}
+
+// Usage of the heap since boot.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+// Heap allocators whose heap can be extended at its end.
+pub unsafe trait ExtendableHeap: GlobalAlloc {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize);
+    // The memory directly behind the current heap end must be mapped and unused.
+    unsafe fn extend(&self, by: usize);
+}
+
+#[cfg(not(feature = "fixed_size_block"))]
+unsafe impl ExtendableHeap for LockedHeap {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size);
+    }
+    unsafe fn extend(&self, by: usize) {
+        self.lock().extend(by);
+    }
+}
+
+// Maps more memory behind the heap end when the inner allocator runs out,
+// using the page table and frame allocator installed in `memory`, and unmaps
+// free memory at the end again.
+pub struct GrowableHeap<A> {
+    heap: A,
+    state: spin::Mutex<HeapState>,
+}
+
+struct HeapState {
+    // Bytes the inner allocator manages, including the released ones.
+    size: usize,
+    // Start and size of unmapped blocks at the heap end, each directly below
+    // the one before. The inner allocator has them allocated, so it doesn't
+    // hand them out.
+    released: [(usize, usize); MAX_RELEASED],
+    released_count: usize,
+}
+
+impl HeapState {
+    fn released_size(&self) -> usize {
+        self.released[..self.released_count]
+            .iter()
+            .map(|&(_, size)| size)
+            .sum()
+    }
+
+    fn mapped(&self) -> usize {
+        self.size - self.released_size()
+    }
+}
+
+impl<A> GrowableHeap<A> {
+    pub const fn new(heap: A) -> Self {
+        GrowableHeap {
+            heap,
+            state: spin::Mutex::new(HeapState {
+                size: 0,
+                released: [(0, 0); MAX_RELEASED],
+                released_count: 0,
+            }),
+        }
+    }
+}
+
+impl<A: ExtendableHeap> GrowableHeap<A> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+        self.state.lock().size = heap_size;
+    }
+
+    // Grows the heap by at least `min_size` bytes. Fails when the heap has
+    // reached `HEAP_MAX_SIZE` or the page table or frame allocator are
+    // unavailable, e.g. because the allocation happens while they are locked.
+    // Interrupts stay disabled while `state` is locked, so an allocation in an
+    // interrupt handler can't spin on it. It still must not come from a handler
+    // that interrupted the inner allocator.
+    fn grow(&self, min_size: usize) -> bool {
+        x86_64::instructions::interrupts::without_interrupts(|| self.grow_locked(min_size))
+    }
+
+    fn grow_locked(&self, min_size: usize) -> bool {
+        use crate::memory::{FRAME_ALLOCATOR, MAPPER};
+
+        let mut state = self.state.lock();
+        if state.size == 0 {
+            return false;
+        }
+        let (mut mapper, mut frame_allocator) =
+            match (MAPPER.try_lock(), FRAME_ALLOCATOR.try_lock()) {
+                (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+                _ => return false,
+            };
+        let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+            _ => return false,
+        };
+        // The inner allocator can only be extended behind its end, so the
+        // released blocks are taken back first.
+        let mut grown = 0;
+        while grown < min_size && state.released_count > 0 {
+            let (start, size) = state.released[state.released_count - 1];
+            let mapped = map_heap_pages(start, size, mapper, frame_allocator);
+            if mapped != size {
+                unmap_heap_pages(start, mapped, mapper, frame_allocator);
+                return false;
+            }
+            let layout = unsafe { Layout::from_size_align_unchecked(size, 4096) };
+            unsafe { self.heap.dealloc(start as *mut u8, layout) };
+            state.released_count -= 1;
+            grown += size;
+        }
+        if grown >= min_size {
+            return true;
+        }
+        let needed = min_size - grown;
+        let by = align_up(needed.max(HEAP_GROW_STEP), 4096).min(HEAP_MAX_SIZE - state.size);
+        if by < needed {
+            return false;
+        }
+        let mapped = map_heap_pages(HEAP_START + state.size, by, mapper, frame_allocator);
+        if mapped > 0 {
+            unsafe { self.heap.extend(mapped) };
+            state.size += mapped;
+        }
+        mapped >= needed
+    }
+
+    // Unmaps free memory at the end of the heap, keeping at least `HEAP_SIZE`.
+    // Returns the number of bytes released.
+    fn shrink(&self) -> usize {
+        x86_64::instructions::interrupts::without_interrupts(|| self.shrink_locked())
+    }
+
+    fn shrink_locked(&self) -> usize {
+        use crate::memory::{FRAME_ALLOCATOR, MAPPER};
+
+        let mut state = self.state.lock();
+        if state.size == 0 || state.released_count == MAX_RELEASED {
+            return 0;
+        }
+        let (mut mapper, mut frame_allocator) =
+            match (MAPPER.try_lock(), FRAME_ALLOCATOR.try_lock()) {
+                (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+                _ => return 0,
+            };
+        let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+            _ => return 0,
+        };
+        let floor = HEAP_START + HEAP_SIZE;
+        let end = HEAP_START + state.mapped();
+        // The inner allocator can't say where its free memory is. The largest
+        // block it can hand out is most likely at the end, so look for that
+        // and then take everything from there to the end.
+        let mut size = end.saturating_sub(floor) & !(4096 - 1);
+        while size >= HEAP_SHRINK_THRESHOLD {
+            let layout = unsafe { Layout::from_size_align_unchecked(size, 4096) };
+            let probe = unsafe { self.heap.alloc(layout) };
+            if probe.is_null() {
+                size = (size / 2) & !(4096 - 1);
+                continue;
+            }
+            unsafe { self.heap.dealloc(probe, layout) };
+            let start = probe as usize;
+            if start < floor || start + size > end {
+                return 0;
+            }
+            // Nothing below `start` fit `size`, so this lands at `start` too
+            // if the memory up to the end is free.
+            let layout = unsafe { Layout::from_size_align_unchecked(end - start, 4096) };
+            let block = unsafe { self.heap.alloc(layout) };
+            if block as usize != start {
+                if !block.is_null() {
+                    unsafe { self.heap.dealloc(block, layout) };
+                }
+                return 0;
+            }
+            unmap_heap_pages(start, end - start, mapper, frame_allocator);
+            let count = state.released_count;
+            state.released[count] = (start, end - start);
+            state.released_count += 1;
+            return end - start;
+        }
+        0
+    }
+}
+
+unsafe impl<A: ExtendableHeap> GlobalAlloc for GrowableHeap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.heap.alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // The new memory may be fragmented from the free space at the old end,
+        // so ask for enough to satisfy the alignment as well.
+        if self.grow(layout.size() + layout.align()) {
+            self.heap.alloc(layout)
+        } else {
+            null_mut()
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout);
+        // Only large blocks can leave enough free memory at the end behind.
+        if layout.size() >= HEAP_GROW_STEP {
+            self.shrink();
+        }
+    }
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
@@ -47,8 +340,32 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
+// Wrapper to implement GlobalAlloc for allocators that need `&mut self`.
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+#[cfg(not(feature = "fixed_size_block"))]
+#[global_allocator]
+static ALLOCATOR: Tracking<GrowableHeap<LockedHeap>> =
+    Tracking::new(GrowableHeap::new(LockedHeap::empty()));
+
+#[cfg(feature = "fixed_size_block")]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Tracking<GrowableHeap<Locked<FixedSizeBlockAllocator>>> = Tracking::new(
+    GrowableHeap::new(Locked::new(FixedSizeBlockAllocator::new())),
+);
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
@@ -0,0 +1,341 @@
+use crate::memory::{self, FRAME_ALLOCATOR};
+use alloc::alloc::Layout;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use spin::Mutex;
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: usize = 4096;
+// A slab holds at least this many objects.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+// Ends the free list of a slab.
+const NO_OBJECT: u16 = u16::MAX;
+
+// Every slab starts with this header, followed by the free list links, one
+// index per object, and then the objects. The links are kept out of the
+// objects so free objects stay in constructed state.
+// Slabs are naturally aligned physical blocks, so the slab of an object is
+// found by aligning its physical address down to the slab size.
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    // Index of the first free object.
+    free: u16,
+    in_use: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlabStats {
+    pub slabs: usize,
+    pub objects_per_slab: usize,
+    pub in_use: usize,
+    pub free: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+struct CacheInner {
+    // Slabs with at least one free object.
+    partial: *mut Slab,
+    registered: bool,
+    stats: SlabStats,
+}
+
+unsafe impl Send for CacheInner {}
+
+// A cache of equally sized objects carved from slabs of physical frames,
+// accessed through the complete physical memory mapping.
+// Objects are constructed with `ctor` once when their slab is created, and
+// must be handed back in constructed state.
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    object_align: usize,
+    ctor: Option<fn(*mut u8)>,
+    inner: Mutex<CacheInner>,
+}
+
+static CACHES: Mutex<Vec<&'static SlabCache>> = Mutex::new(Vec::new());
+
+impl SlabCache {
+    pub const fn new(name: &'static str, layout: Layout, ctor: Option<fn(*mut u8)>) -> Self {
+        let align = layout.align();
+        let size = if layout.size() > 0 { layout.size() } else { 1 };
+        SlabCache {
+            name,
+            object_size: (size + align - 1) / align * align,
+            object_align: align,
+            ctor,
+            inner: Mutex::new(CacheInner {
+                partial: ptr::null_mut(),
+                registered: false,
+                stats: SlabStats {
+                    slabs: 0,
+                    objects_per_slab: 0,
+                    in_use: 0,
+                    free: 0,
+                    allocations: 0,
+                    frees: 0,
+                },
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let mut stats = self.inner.lock().stats;
+        stats.objects_per_slab = self.objects_per_slab();
+        stats
+    }
+
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        let mut inner = self.inner.lock();
+        if inner.partial.is_null() {
+            let slab = self.new_slab()?;
+            unsafe { push(&mut inner.partial, slab) };
+            inner.stats.slabs += 1;
+            inner.stats.free += self.objects_per_slab();
+            if !inner.registered {
+                inner.registered = true;
+                CACHES.lock().push(self);
+            }
+        }
+        let slab = inner.partial;
+        unsafe {
+            let index = (*slab).free;
+            (*slab).free = *self.links(slab).add(usize::from(index));
+            (*slab).in_use += 1;
+            if (*slab).free == NO_OBJECT {
+                unlink(&mut inner.partial, slab);
+            }
+            inner.stats.in_use += 1;
+            inner.stats.free -= 1;
+            inner.stats.allocations += 1;
+            NonNull::new(self.object(slab, index))
+        }
+    }
+
+    // `ptr` must have been returned by `alloc` of this cache.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        let mut inner = self.inner.lock();
+        let slab = self.slab_of(ptr);
+        let offset = ptr.as_ptr() as usize - slab as usize - self.objects_offset();
+        let index = (offset / self.object_size) as u16;
+        if (*slab).free == NO_OBJECT {
+            push(&mut inner.partial, slab);
+        }
+        *self.links(slab).add(usize::from(index)) = (*slab).free;
+        (*slab).free = index;
+        (*slab).in_use -= 1;
+        inner.stats.in_use -= 1;
+        inner.stats.free += 1;
+        inner.stats.frees += 1;
+    }
+
+    // Gives the frames of all completely free slabs back to the frame allocator.
+    // Returns the number of released slabs.
+    pub fn shrink(&self) -> usize {
+        let mut inner = self.inner.lock();
+        let mut released = 0;
+        let mut slab = inner.partial;
+        while !slab.is_null() {
+            let next = unsafe { (*slab).next };
+            if unsafe { (*slab).in_use } == 0 {
+                unsafe {
+                    unlink(&mut inner.partial, slab);
+                    self.free_slab(slab);
+                }
+                inner.stats.slabs -= 1;
+                inner.stats.free -= self.objects_per_slab();
+                released += 1;
+            }
+            slab = next;
+        }
+        released
+    }
+
+    fn slab_frames(&self) -> usize {
+        let size = mem::size_of::<Slab>()
+            + (mem::size_of::<u16>() + self.object_size) * MIN_OBJECTS_PER_SLAB
+            + self.object_align;
+        let frames = (size + FRAME_SIZE - 1) / FRAME_SIZE;
+        frames.next_power_of_two()
+    }
+
+    // Offset of the first object when the slab holds `count` objects.
+    fn objects_offset_for(&self, count: usize) -> usize {
+        let links_end = mem::size_of::<Slab>() + count * mem::size_of::<u16>();
+        (links_end + self.object_align - 1) / self.object_align * self.object_align
+    }
+
+    fn objects_offset(&self) -> usize {
+        self.objects_offset_for(self.objects_per_slab())
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        let slab_size = self.slab_frames() * FRAME_SIZE;
+        let per_object = mem::size_of::<u16>() + self.object_size;
+        let mut count = (slab_size - mem::size_of::<Slab>()) / per_object;
+        // The alignment of the objects may cost one of them.
+        while self.objects_offset_for(count) + count * self.object_size > slab_size {
+            count -= 1;
+        }
+        count.min(usize::from(NO_OBJECT))
+    }
+
+    unsafe fn links(&self, slab: *mut Slab) -> *mut u16 {
+        slab.add(1) as *mut u16
+    }
+
+    unsafe fn object(&self, slab: *mut Slab, index: u16) -> *mut u8 {
+        (slab as *mut u8).add(self.objects_offset() + usize::from(index) * self.object_size)
+    }
+
+    fn new_slab(&self) -> Option<*mut Slab> {
+        let frames = self.slab_frames();
+        let range = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()?
+            .allocate_contiguous(frames, frames)?;
+        let start = memory::phys_to_virt(range.start.start_address());
+        let slab: *mut Slab = start.as_mut_ptr();
+        let count = self.objects_per_slab();
+        // Objects are handed out in address order.
+        for index in 0..count as u16 {
+            let next = if usize::from(index) + 1 < count {
+                index + 1
+            } else {
+                NO_OBJECT
+            };
+            unsafe {
+                *self.links(slab).add(usize::from(index)) = next;
+                if let Some(ctor) = self.ctor {
+                    ctor(self.object(slab, index));
+                }
+            }
+        }
+        unsafe {
+            slab.write(Slab {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free: 0,
+                in_use: 0,
+            })
+        };
+        Some(slab)
+    }
+
+    unsafe fn free_slab(&self, slab: *mut Slab) {
+        let frames = self.slab_frames() as u64;
+        let phys = VirtAddr::from_ptr(slab) - memory::physical_memory_offset();
+        let start: PhysFrame<Size4KiB> = PhysFrame::containing_address(PhysAddr::new(phys));
+        if let Some(frame_allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            frame_allocator.deallocate_contiguous(PhysFrame::range(start, start + frames));
+        }
+    }
+
+    fn slab_of(&self, ptr: NonNull<u8>) -> *mut Slab {
+        let slab_size = (self.slab_frames() * FRAME_SIZE) as u64;
+        let phys = VirtAddr::from_ptr(ptr.as_ptr()) - memory::physical_memory_offset();
+        memory::phys_to_virt(PhysAddr::new(phys).align_down(slab_size)).as_mut_ptr()
+    }
+}
+
+unsafe fn push(head: &mut *mut Slab, slab: *mut Slab) {
+    (*slab).prev = ptr::null_mut();
+    (*slab).next = *head;
+    if !head.is_null() {
+        (**head).prev = slab;
+    }
+    *head = slab;
+}
+
+unsafe fn unlink(head: &mut *mut Slab, slab: *mut Slab) {
+    if (*slab).prev.is_null() {
+        *head = (*slab).next;
+    } else {
+        (*(*slab).prev).next = (*slab).next;
+    }
+    if !(*slab).next.is_null() {
+        (*(*slab).next).prev = (*slab).prev;
+    }
+    (*slab).next = ptr::null_mut();
+    (*slab).prev = ptr::null_mut();
+}
+
+// Calls `f` for every cache that has allocated a slab.
+pub fn for_each_cache(mut f: impl FnMut(&'static SlabCache)) {
+    for &cache in CACHES.lock().iter() {
+        f(cache);
+    }
+}
+
+// A slab cache for values of type `T`.
+pub struct ObjectCache<T> {
+    cache: SlabCache,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for ObjectCache<T> {}
+
+impl<T> ObjectCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        ObjectCache {
+            cache: SlabCache::new(name, Layout::new::<T>(), None),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        let ptr = self.cache.alloc()?.cast::<T>();
+        unsafe { ptr.as_ptr().write(value) };
+        Some(SlabBox { ptr, cache: self })
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.cache.stats()
+    }
+
+    pub fn shrink(&self) -> usize {
+        self.cache.shrink()
+    }
+}
+
+// Owning pointer to a value in an `ObjectCache`, like `Box`.
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static ObjectCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.cache.free(self.ptr.cast());
+        }
+    }
+}
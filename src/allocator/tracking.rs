@@ -0,0 +1,224 @@
+use super::fixed_size_block::BLOCK_SIZES;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// One class per block size of the fixed-size block allocator, plus one for
+// everything larger.
+pub const SIZE_CLASSES: usize = BLOCK_SIZES.len() + 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub allocations: u64,
+    pub live: u64,
+}
+
+// Snapshot of the heap usage recorded by `Tracking`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub size_classes: [SizeClassStats; SIZE_CLASSES],
+}
+
+impl HeapStats {
+    pub fn live_allocations(&self) -> u64 {
+        self.allocations - self.deallocations
+    }
+}
+
+// Upper bound of the size class at `index`, `None` for the class of large allocations.
+pub fn size_class_limit(index: usize) -> Option<usize> {
+    BLOCK_SIZES.get(index).copied()
+}
+
+fn size_class(layout: &Layout) -> usize {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES
+        .iter()
+        .position(|&s| s >= required_block_size)
+        .unwrap_or(BLOCK_SIZES.len())
+}
+
+// Wraps a global allocator and records its usage.
+pub struct Tracking<A> {
+    inner: A,
+    bytes_in_use: AtomicUsize,
+    peak_bytes_in_use: AtomicUsize,
+    allocations: AtomicU64,
+    deallocations: AtomicU64,
+    class_allocations: [AtomicU64; SIZE_CLASSES],
+    class_live: [AtomicU64; SIZE_CLASSES],
+}
+
+impl<A> Tracking<A> {
+    pub const fn new(inner: A) -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Tracking {
+            inner,
+            bytes_in_use: AtomicUsize::new(0),
+            peak_bytes_in_use: AtomicUsize::new(0),
+            allocations: AtomicU64::new(0),
+            deallocations: AtomicU64::new(0),
+            class_allocations: [ZERO; SIZE_CLASSES],
+            class_live: [ZERO; SIZE_CLASSES],
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            bytes_in_use: self.bytes_in_use.load(Ordering::Relaxed),
+            peak_bytes_in_use: self.peak_bytes_in_use.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            ..HeapStats::default()
+        };
+        for (index, class) in stats.size_classes.iter_mut().enumerate() {
+            class.allocations = self.class_allocations[index].load(Ordering::Relaxed);
+            class.live = self.class_live[index].load(Ordering::Relaxed);
+        }
+        stats
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Tracking<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            let in_use = self
+                .bytes_in_use
+                .fetch_add(layout.size(), Ordering::Relaxed);
+            self.peak_bytes_in_use
+                .fetch_max(in_use + layout.size(), Ordering::Relaxed);
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            let class = size_class(&layout);
+            self.class_allocations[class].fetch_add(1, Ordering::Relaxed);
+            self.class_live[class].fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "heap_trace")]
+            trace::record(ptr, layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "heap_trace")]
+        trace::forget(ptr);
+        self.inner.dealloc(ptr, layout);
+        self.bytes_in_use
+            .fetch_sub(layout.size(), Ordering::Relaxed);
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.class_live[size_class(&layout)].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Records the callers of live allocations. Backtraces follow the saved frame
+// pointers, so the kernel must be built with `-C force-frame-pointers=yes`.
+#[cfg(feature = "heap_trace")]
+pub mod trace {
+    use alloc::alloc::Layout;
+    use spin::Mutex;
+
+    pub const MAX_TRACKED: usize = 1024;
+    pub const BACKTRACE_DEPTH: usize = 4;
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct LiveAllocation {
+        pub ptr: usize,
+        pub size: usize,
+        // Return addresses, innermost first. Unused entries are zero.
+        pub backtrace: [usize; BACKTRACE_DEPTH],
+    }
+
+    struct Table {
+        entries: [Option<LiveAllocation>; MAX_TRACKED],
+        // Allocations that didn't fit into the table since the last `clear`.
+        untracked: usize,
+    }
+
+    static TABLE: Mutex<Table> = Mutex::new(Table {
+        entries: [None; MAX_TRACKED],
+        untracked: 0,
+    });
+
+    pub(super) fn record(ptr: *mut u8, layout: Layout) {
+        let allocation = LiveAllocation {
+            ptr: ptr as usize,
+            size: layout.size(),
+            backtrace: backtrace(),
+        };
+        let mut table = TABLE.lock();
+        match table.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => *entry = Some(allocation),
+            None => table.untracked += 1,
+        }
+    }
+
+    pub(super) fn forget(ptr: *mut u8) {
+        let mut table = TABLE.lock();
+        let entry = table
+            .entries
+            .iter_mut()
+            .find(|entry| matches!(entry, Some(a) if a.ptr == ptr as usize));
+        if let Some(entry) = entry {
+            *entry = None;
+        }
+    }
+
+    // Calls `f` for every tracked live allocation.
+    // `f` must not allocate.
+    pub fn for_each_live_allocation(mut f: impl FnMut(&LiveAllocation)) {
+        for allocation in TABLE.lock().entries.iter().flatten() {
+            f(allocation);
+        }
+    }
+
+    // Number of allocations that were not tracked because the table was full,
+    // counted since boot or the last `clear`. Doesn't go down when they are freed.
+    pub fn untracked() -> usize {
+        TABLE.lock().untracked
+    }
+
+    // Stops tracking the current allocations, so only later ones are traced.
+    pub fn clear() {
+        let mut table = TABLE.lock();
+        table.entries = [None; MAX_TRACKED];
+        table.untracked = 0;
+    }
+
+    #[inline(always)]
+    fn backtrace() -> [usize; BACKTRACE_DEPTH] {
+        use crate::memory::stack;
+        use x86_64::VirtAddr;
+
+        let mut backtrace = [0; BACKTRACE_DEPTH];
+        let (rsp, mut frame): (usize, usize);
+        unsafe {
+            core::arch::asm!("mov {}, rsp", "mov {}, rbp", out(reg) rsp, out(reg) frame);
+        }
+        // Only frames on the current stack are followed, anything else is a
+        // caller without frame pointers or garbage.
+        let top = match stack::bounds(VirtAddr::new(rsp as u64)) {
+            Some((_, top)) => top.as_u64() as usize,
+            None => return backtrace,
+        };
+        for entry in backtrace.iter_mut() {
+            if frame < rsp || frame + 16 > top || frame % 8 != 0 {
+                break;
+            }
+            let frame_ptr = frame as *const usize;
+            let (next, return_address) = unsafe { (*frame_ptr, *frame_ptr.add(1)) };
+            *entry = return_address;
+            // Stacks grow down, so the caller's frame is always above ours.
+            if next <= frame {
+                break;
+            }
+            frame = next;
+        }
+        backtrace
+    }
+}
@@ -1,4 +1,7 @@
+use crate::memory::{stack, vmm::VmError};
 use crate::println_info;
+use core::cell::UnsafeCell;
+use core::ptr;
 use lazy_static::lazy_static;
 use x86_64::instructions::segmentation::Segment;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
@@ -6,26 +9,26 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const IST_STACK_SIZE: u64 = 4096 * 5;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+// The IST stacks start out as statics, until `init_stacks` replaces them
+// with stacks that have a guard page.
+// The CPU reads the TSS, Rust code only writes to it through raw pointers,
+// so no reference to it outlives the creation of its descriptor.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
+static TSS: Tss = Tss(UnsafeCell::new(TaskStateSegment::new()));
+
+const BOOT_STACK_SIZE: usize = 4096 * 5;
+static mut DOUBLE_FAULT_BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
         (
             gdt,
             Selectors {
@@ -44,6 +47,8 @@ struct Selectors {
 pub fn init() {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
+    let stack_start = VirtAddr::from_ptr(unsafe { ptr::addr_of!(DOUBLE_FAULT_BOOT_STACK) });
+    set_ist(DOUBLE_FAULT_IST_INDEX, stack_start + BOOT_STACK_SIZE);
     GDT.0.load();
     unsafe {
         set_cs(GDT.1.code_selector);
@@ -51,3 +56,19 @@ pub fn init() {
     }
     println_info!("GDT loaded.");
 }
+
+// Moves the IST stacks to stacks with a guard page below them.
+// Needs the memory manager, so it runs after `memory::install`.
+pub fn init_stacks() -> Result<(), VmError> {
+    let stack = stack::allocate("double fault", IST_STACK_SIZE)?;
+    set_ist(DOUBLE_FAULT_IST_INDEX, stack.top());
+    println_info!("IST stacks allocated.");
+    Ok(())
+}
+
+fn set_ist(index: u16, top: VirtAddr) {
+    unsafe {
+        let table = ptr::addr_of_mut!((*TSS.0.get()).interrupt_stack_table);
+        (*table)[usize::from(index)] = top;
+    }
+}
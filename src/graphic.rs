@@ -1,3 +1,4 @@
+use crate::sync::IrqSpinlock;
 use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
@@ -16,14 +17,17 @@ lazy_static! {
 }
 
 lazy_static! {
-    pub static ref TEXT_WRITER: Mutex<TextWriter> = Mutex::new(TextWriter {
-        column_pos: 0,
-        bg_color: 0x00,
-        fg_color: 0xff,
-        text_buffer: TextBuffer {
-            chars: [[ScreenChar::new(); TEXT_BUFFER_WIDTH]; TEXT_BUFFER_HEIGHT]
-        },
-    });
+    pub static ref TEXT_WRITER: IrqSpinlock<TextWriter> = IrqSpinlock::new(
+        "TEXT_WRITER",
+        TextWriter {
+            column_pos: 0,
+            bg_color: 0x00,
+            fg_color: 0xff,
+            text_buffer: TextBuffer {
+                chars: [[ScreenChar::new(); TEXT_BUFFER_WIDTH]; TEXT_BUFFER_HEIGHT]
+            },
+        }
+    );
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -150,10 +154,7 @@ impl fmt::Write for TextWriter {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    interrupts::without_interrupts(|| {
-        TEXT_WRITER.lock().write_fmt(args).unwrap();
-    });
+    TEXT_WRITER.lock().write_fmt(args).unwrap();
 }
 
 use crate::serial_print;
@@ -1,22 +1,31 @@
 use crate::hlt_loop;
+use crate::percpu;
+use crate::sync::IrqSpinlock;
 use crate::{gdt, print, println, println_info};
+use irq::IrqReturn;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
+pub mod apic;
+pub mod irq;
+
 pub const PIC_1_OFFSET: u8 = 0x20;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSpinlock<ChainedPics> = IrqSpinlock::new("PICS", unsafe {
+    ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
+});
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Serial = PIC_1_OFFSET + 4,
+    Rtc = PIC_2_OFFSET,
     Mouse = PIC_2_OFFSET + 4,
 }
 
@@ -27,6 +36,36 @@ impl InterruptIndex {
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+    // The ISA IRQ, the vectors are the same with the PICs and the I/O APIC.
+    fn isa_irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+// Acknowledges an interrupt at whichever controller delivered it.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    end_of_interrupt_vector(index.as_u8());
+}
+
+pub(crate) fn end_of_interrupt_vector(vector: u8) {
+    match apic::local_apic() {
+        Some(local_apic) => local_apic.end_of_interrupt(),
+        None => unsafe { PICS.lock().notify_end_of_interrupt(vector) },
+    }
+}
+
+// Switches from the 8259 PICs to the local APIC and I/O APIC if the MADT lists
+// them. Maps their registers, so it has to run after `memory::install`.
+// The PICs stay in use if there is no APIC. The HPET takes over the timer
+// interrupt if `timer::init_hpet` ran before.
+pub fn init_apic() -> Result<(), apic::ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        apic::init(InterruptIndex::Timer.as_u8())?;
+        unsafe { PICS.lock().write_masks(0xff, 0xff) };
+        irq::enable_lines()?;
+        println_info!("APIC initialized.");
+        Ok(())
+    })
 }
 
 lazy_static! {
@@ -40,14 +79,23 @@ lazy_static! {
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        //idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        // Also tells spurious interrupts of the PICs on IRQ 7 and 15 apart.
+        irq::install(&mut idt);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
 pub fn init_idt() {
     IDT.load();
+    let register = |index: InterruptIndex, name, handler| {
+        irq::register_irq(index.isa_irq(), name, handler, 0)
+            .expect("Registering an interrupt handler failed.");
+    };
+    register(InterruptIndex::Keyboard, "keyboard", keyboard_handler);
+    register(InterruptIndex::Serial, "serial", serial_handler);
+    register(InterruptIndex::Rtc, "rtc", rtc_handler);
+    register(InterruptIndex::Mouse, "mouse", mouse_handler);
     println_info!("IDT loaded.");
 }
 
@@ -59,11 +107,18 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    use crate::memory::fault;
     use x86_64::registers::control::Cr2;
 
+    let addr = Cr2::read();
+    let error = match fault::handle_page_fault(addr, error_code) {
+        Ok(()) => return,
+        Err(error) => error,
+    };
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {:?}", addr);
     println!("Error Code: {:?}", error_code);
+    println!("Reason: {:?}", error);
     println!("{:#?}", stack_frame);
     hlt_loop();
 }
@@ -72,20 +127,28 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    use crate::memory::stack;
+    use x86_64::registers::control::Cr2;
+
+    // A fault on a guard page usually can't be handled on the overflowed
+    // stack, so it ends up here.
+    if let Some(name) = stack::guard_page_owner(Cr2::read()) {
+        panic!(
+            "EXCEPTION: DOUBLE FAULT\nStack overflow: {} stack\n{:#?}",
+            name, stack_frame
+        );
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use crate::timer::TIMER;
-    unsafe { TIMER.force_unlock() };
-    TIMER.lock().inc();
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    let _irq = percpu::InterruptGuard::enter();
+    irq::count(InterruptIndex::Timer.as_u8());
+    crate::timer::tick();
+    end_of_interrupt(InterruptIndex::Timer);
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_handler(_data: usize) -> IrqReturn {
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
@@ -105,19 +168,31 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
             }
         }
     }
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    IrqReturn::Handled
 }
 
-extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn mouse_handler(_data: usize) -> IrqReturn {
     use x86_64::instructions::port::Port;
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    print!("{}", scancode);
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
-    }
+    // There's no mouse driver yet, the byte is read so the controller
+    // sends the next one.
+    let mut port: Port<u8> = Port::new(0x60);
+    unsafe { port.read() };
+    IrqReturn::Handled
 }
+
+fn serial_handler(_data: usize) -> IrqReturn {
+    use x86_64::instructions::port::Port;
+    // Reading the received byte acknowledges the interrupt at the UART.
+    let mut port = Port::new(0x3f8);
+    let byte: u8 = unsafe { port.read() };
+    print!("{}", byte as char);
+    IrqReturn::Handled
+}
+
+fn rtc_handler(_data: usize) -> IrqReturn {
+    crate::rtc::handle_interrupt();
+    IrqReturn::Handled
+}
+
+// Spurious interrupts must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
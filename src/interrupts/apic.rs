@@ -0,0 +1,243 @@
+use crate::acpi::madt::Madt;
+use crate::memory::mmio::{CachePolicy, MmioRegion};
+use crate::memory::vmm::VmError;
+use crate::timer;
+use crate::timer::hpet::{self, HpetError};
+use core::time::Duration;
+use spin::{Mutex, Once};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers.
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+const LVT_ERROR: usize = 0x370;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+pub struct LocalApic {
+    registers: MmioRegion,
+}
+
+impl LocalApic {
+    fn read(&self, register: usize) -> u32 {
+        self.registers.read(register)
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        self.registers.write(register, value)
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(EOI, 0);
+    }
+
+    // Fires `vector` `frequency` times per second.
+    fn start_timer(&self, vector: u8, frequency: u32) {
+        self.write(TIMER_DIVIDE, DIVIDE_BY_16);
+        let ticks = calibrate_timer(self) / frequency;
+        self.write(LVT_TIMER, TIMER_PERIODIC | u32::from(vector));
+        self.write(TIMER_INITIAL_COUNT, ticks.max(1));
+    }
+}
+
+// Counts the APIC timer ticks in one second, measured for 10ms.
+fn calibrate_timer(apic: &LocalApic) -> u32 {
+    const SAMPLE_HZ: u32 = 100;
+    apic.write(TIMER_INITIAL_COUNT, u32::MAX);
+    timer::delay(Duration::from_secs(1) / SAMPLE_HZ);
+    let elapsed = u32::MAX - apic.read(TIMER_CURRENT_COUNT);
+    apic.write(TIMER_INITIAL_COUNT, 0);
+    elapsed * SAMPLE_HZ
+}
+
+// I/O APIC registers, accessed through a select and a window register.
+const IO_REGISTER_SELECT: usize = 0x00;
+const IO_WINDOW: usize = 0x10;
+const IO_VERSION: u32 = 0x01;
+const IO_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+pub struct IoApic {
+    registers: MmioRegion,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    fn map(address: PhysAddr, gsi_base: u32) -> Result<IoApic, VmError> {
+        let registers = MmioRegion::map("io apic", address, 0x20, CachePolicy::Uncached)?;
+        let mut io_apic = IoApic {
+            registers,
+            gsi_base,
+            pins: 0,
+        };
+        io_apic.pins = ((io_apic.read(IO_VERSION) >> 16) & 0xff) + 1;
+        Ok(io_apic)
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        self.registers.write(IO_REGISTER_SELECT, register);
+        self.registers.read(IO_WINDOW)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.registers.write(IO_REGISTER_SELECT, register);
+        self.registers.write(IO_WINDOW, value);
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.pins
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IO_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        self.write(register, entry as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+    }
+
+    fn redirection(&self, gsi: u32) -> u64 {
+        let register = IO_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        u64::from(self.read(register)) | u64::from(self.read(register + 1)) << 32
+    }
+
+    pub fn set_masked(&self, gsi: u32, masked: bool) {
+        let entry = self.redirection(gsi);
+        self.set_redirection(
+            gsi,
+            if masked {
+                entry | REDIRECTION_MASKED
+            } else {
+                entry & !REDIRECTION_MASKED
+            },
+        );
+    }
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+static IO_APIC: Mutex<Option<IoApic>> = Mutex::new(None);
+static MADT: Once<Madt> = Once::new();
+
+// The local APIC, once `init` found and enabled it.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.r#try()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    NoMadt,
+    // `init` hasn't enabled the APICs, because it wasn't called or failed.
+    NotInitialized,
+    NoIoApic,
+    Map(VmError),
+}
+
+// Enables the local APIC and its timer and the I/O APIC that handles the ISA
+// IRQs. The caller disables the 8259 PICs afterwards.
+pub fn init(timer_vector: u8) -> Result<(), ApicError> {
+    let madt = Madt::find().ok_or(ApicError::NoMadt)?;
+    let madt = *MADT.call_once(|| madt);
+    let info = madt.io_apic_for(0).ok_or(ApicError::NoIoApic)?;
+    let io_apic = IoApic::map(info.address, info.gsi_base).map_err(ApicError::Map)?;
+    for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.pins {
+        io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+    }
+    *IO_APIC.lock() = Some(io_apic);
+
+    let mut base = Msr::new(IA32_APIC_BASE);
+    unsafe { base.write(base.read() | APIC_BASE_ENABLE) };
+    let registers = MmioRegion::map(
+        "local apic",
+        madt.local_apic_address,
+        0x400,
+        CachePolicy::Uncached,
+    )
+    .map_err(ApicError::Map)?;
+    let apic = LOCAL_APIC.call_once(|| LocalApic { registers });
+    apic.write(LVT_LINT0, LVT_MASKED);
+    apic.write(LVT_LINT1, LVT_MASKED);
+    apic.write(LVT_ERROR, LVT_MASKED);
+    apic.write(TASK_PRIORITY, 0);
+    apic.write(SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+    // The HPET runs at an exact rate, the APIC timer only as exact as its calibration.
+    if start_hpet_timer(&madt, apic, timer_vector).is_err() {
+        apic.start_timer(timer_vector, timer::frequency());
+        timer::set_tick_period(1_000_000_000 / u64::from(timer::frequency()));
+    }
+    Ok(())
+}
+
+// Delivers HPET comparator 0 to `vector` if there is an HPET and an I/O APIC
+// input it can reach. Prefers the PIT's input, which is free once the PIT stops.
+fn start_hpet_timer(madt: &Madt, apic: &LocalApic, vector: u8) -> Result<(), HpetError> {
+    let routes = hpet::hpet().ok_or(HpetError::NotFound)?.routes(0)?;
+    let io_apic = IO_APIC.lock();
+    let io_apic = io_apic.as_ref().ok_or(HpetError::RouteUnavailable)?;
+    let gsi = core::iter::once(madt.isa_irq(0).gsi)
+        .chain(16..32)
+        .find(|&gsi| gsi < 32 && routes & 1 << gsi != 0 && io_apic.handles(gsi))
+        .ok_or(HpetError::RouteUnavailable)?;
+    timer::start_hpet_ticks(gsi as u8)?;
+    // Edge triggered and active high, like the PIT.
+    io_apic.set_redirection(gsi, u64::from(vector) | u64::from(apic.id()) << 56);
+    Ok(())
+}
+
+// Delivers ISA `irq` to `vector` on this CPU, following the MADT's overrides.
+pub fn route_isa_irq(irq: u8, vector: u8, masked: bool) -> Result<(), ApicError> {
+    let madt = MADT.r#try().ok_or(ApicError::NotInitialized)?;
+    let apic = local_apic().ok_or(ApicError::NotInitialized)?;
+    let isa_irq = madt.isa_irq(irq);
+    let io_apic = IO_APIC.lock();
+    let io_apic = io_apic
+        .as_ref()
+        .filter(|io_apic| io_apic.handles(isa_irq.gsi))
+        .ok_or(ApicError::NoIoApic)?;
+    let mut entry = u64::from(vector) | u64::from(apic.id()) << 56;
+    if isa_irq.active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if isa_irq.level_triggered {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    if masked {
+        entry |= REDIRECTION_MASKED;
+    }
+    io_apic.set_redirection(isa_irq.gsi, entry);
+    Ok(())
+}
+
+pub fn set_isa_irq_masked(irq: u8, masked: bool) -> Result<(), ApicError> {
+    let madt = MADT.r#try().ok_or(ApicError::NotInitialized)?;
+    let gsi = madt.isa_irq(irq).gsi;
+    let io_apic = IO_APIC.lock();
+    let io_apic = io_apic
+        .as_ref()
+        .filter(|io_apic| io_apic.handles(gsi))
+        .ok_or(ApicError::NoIoApic)?;
+    io_apic.set_masked(gsi, masked);
+    Ok(())
+}
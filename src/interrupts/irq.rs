@@ -0,0 +1,371 @@
+use super::{apic, PICS, PIC_1_OFFSET};
+use crate::percpu;
+use crate::println;
+use crate::sync::IrqSpinlock;
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+// Handlers that can share one vector.
+pub const MAX_SHARED: usize = 4;
+// Vectors that aren't tied to an ISA IRQ, for `allocate_vector`.
+pub const DYNAMIC_VECTORS: RangeInclusive<u8> = 0x30..=0x3f;
+// The vectors with a dispatcher, the ISA IRQs followed by the dynamic ones.
+const FIRST_VECTOR: u8 = PIC_1_OFFSET;
+const VECTORS: usize = 0x20;
+const ISA_IRQS: u8 = 16;
+// 0 is the timer, which has a fixed handler, and 2 connects the PICs.
+const RESERVED_ISA_IRQS: u16 = 1 << 0 | 1 << 2;
+// Commands to the PICs' command ports.
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_EOI: u8 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    // The interrupt came from another device on a shared line.
+    NotHandled,
+}
+
+pub type Handler = fn(data: usize) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq,
+    InvalidVector,
+    TooManyHandlers,
+    NoFreeVector,
+    NotRegistered,
+    Apic(apic::ApicError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    slot: u8,
+    // Tells apart the handlers that used the slot over time.
+    generation: u32,
+}
+
+impl HandlerId {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorStats {
+    pub count: u64,
+    // Interrupts that none of the handlers claimed.
+    pub unhandled: u64,
+    pub handlers: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    name: &'static str,
+    handler: Handler,
+    data: usize,
+}
+
+type Chain = [Option<Entry>; MAX_SHARED];
+
+struct Table {
+    chains: [Chain; VECTORS],
+    generations: [[u32; MAX_SHARED]; VECTORS],
+    // ISA IRQs that have handlers and are unmasked.
+    enabled_lines: u16,
+    // Dynamic vectors handed out by `allocate_vector`.
+    allocated: u16,
+}
+
+static TABLE: IrqSpinlock<Table> = IrqSpinlock::new(
+    "irq table",
+    Table {
+        chains: [[None; MAX_SHARED]; VECTORS],
+        generations: [[0; MAX_SHARED]; VECTORS],
+        enabled_lines: 0,
+        allocated: 0,
+    },
+);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; 256] = [ZERO; 256];
+static UNHANDLED: [AtomicU64; 256] = [ZERO; 256];
+
+fn isa_vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+fn isa_irq(vector: u8) -> Option<u8> {
+    vector
+        .checked_sub(PIC_1_OFFSET)
+        .filter(|&irq| irq < ISA_IRQS)
+}
+
+fn chain_index(vector: u8) -> usize {
+    usize::from(vector - FIRST_VECTOR)
+}
+
+fn dynamic_bit(vector: u8) -> u16 {
+    1 << (vector - DYNAMIC_VECTORS.start())
+}
+
+impl Table {
+    fn add(&mut self, vector: u8, entry: Entry) -> Result<HandlerId, IrqError> {
+        let chain = &mut self.chains[chain_index(vector)];
+        let slot = chain
+            .iter()
+            .position(Option::is_none)
+            .ok_or(IrqError::TooManyHandlers)?;
+        chain[slot] = Some(entry);
+        let generation = &mut self.generations[chain_index(vector)][slot];
+        *generation = generation.wrapping_add(1);
+        Ok(HandlerId {
+            vector,
+            slot: slot as u8,
+            generation: *generation,
+        })
+    }
+
+    fn is_empty(&self, vector: u8) -> bool {
+        self.chains[chain_index(vector)].iter().all(Option::is_none)
+    }
+
+    fn is_allocated(&self, vector: u8) -> bool {
+        self.allocated & dynamic_bit(vector) != 0
+    }
+}
+
+// Adds `handler` to ISA `irq` and unmasks the line at the PIC or I/O APIC if
+// it is the first one. Handlers of a shared line are called in turn.
+pub fn register_irq(
+    irq: u8,
+    name: &'static str,
+    handler: Handler,
+    data: usize,
+) -> Result<HandlerId, IrqError> {
+    if irq >= ISA_IRQS || RESERVED_ISA_IRQS & 1 << irq != 0 {
+        return Err(IrqError::InvalidIrq);
+    }
+    let mut table = TABLE.lock();
+    let id = table.add(
+        isa_vector(irq),
+        Entry {
+            name,
+            handler,
+            data,
+        },
+    )?;
+    if table.enabled_lines & 1 << irq == 0 {
+        table.enabled_lines |= 1 << irq;
+        if let Err(error) = update_line(irq, table.enabled_lines) {
+            table.enabled_lines &= !(1 << irq);
+            table.chains[chain_index(id.vector)][usize::from(id.slot)] = None;
+            return Err(IrqError::Apic(error));
+        }
+    }
+    Ok(id)
+}
+
+// Adds `handler` to a vector from `allocate_vector`. Whatever raises the
+// vector, e.g. an MSI, has to be set up by the caller.
+pub fn register_vector(
+    vector: u8,
+    name: &'static str,
+    handler: Handler,
+    data: usize,
+) -> Result<HandlerId, IrqError> {
+    if !DYNAMIC_VECTORS.contains(&vector) {
+        return Err(IrqError::InvalidVector);
+    }
+    let mut table = TABLE.lock();
+    if !table.is_allocated(vector) {
+        return Err(IrqError::InvalidVector);
+    }
+    table.add(
+        vector,
+        Entry {
+            name,
+            handler,
+            data,
+        },
+    )
+}
+
+// Reserves a dynamic vector that has no handlers yet.
+pub fn allocate_vector() -> Result<u8, IrqError> {
+    let mut table = TABLE.lock();
+    let vector = DYNAMIC_VECTORS
+        .find(|&vector| !table.is_allocated(vector) && table.is_empty(vector))
+        .ok_or(IrqError::NoFreeVector)?;
+    table.allocated |= dynamic_bit(vector);
+    Ok(vector)
+}
+
+pub fn free_vector(vector: u8) {
+    if DYNAMIC_VECTORS.contains(&vector) {
+        TABLE.lock().allocated &= !dynamic_bit(vector);
+    }
+}
+
+// Removes a handler, masking its ISA line if it was the last one.
+pub fn unregister(id: HandlerId) -> Result<(), IrqError> {
+    let mut table = TABLE.lock();
+    let (index, slot) = (chain_index(id.vector), usize::from(id.slot));
+    // The slot was reused, so the handler in it isn't ours.
+    if table.generations[index][slot] != id.generation {
+        return Err(IrqError::NotRegistered);
+    }
+    if table.chains[index][slot].take().is_none() {
+        return Err(IrqError::NotRegistered);
+    }
+    if let Some(irq) = isa_irq(id.vector) {
+        if table.is_empty(id.vector) {
+            table.enabled_lines &= !(1 << irq);
+            update_line(irq, table.enabled_lines).map_err(IrqError::Apic)?;
+        }
+    }
+    Ok(())
+}
+
+fn pic_masks(enabled_lines: u16) -> (u8, u8) {
+    // The timer and the line to the second PIC are always on.
+    let masks = !(enabled_lines | 1 << 0 | 1 << 2);
+    (masks as u8, (masks >> 8) as u8)
+}
+
+fn update_line(irq: u8, enabled_lines: u16) -> Result<(), apic::ApicError> {
+    if apic::local_apic().is_some() {
+        let masked = enabled_lines & 1 << irq == 0;
+        apic::route_isa_irq(irq, isa_vector(irq), masked)
+    } else {
+        let (primary, secondary) = pic_masks(enabled_lines);
+        unsafe { PICS.lock().write_masks(primary, secondary) };
+        Ok(())
+    }
+}
+
+// Unmasks the ISA lines that have handlers at whichever controller is in use.
+pub(crate) fn enable_lines() -> Result<(), apic::ApicError> {
+    let table = TABLE.lock();
+    if apic::local_apic().is_some() {
+        for irq in (0..ISA_IRQS).filter(|irq| table.enabled_lines & 1 << irq != 0) {
+            update_line(irq, table.enabled_lines)?;
+        }
+        Ok(())
+    } else {
+        update_line(0, table.enabled_lines)
+    }
+}
+
+pub fn stats(vector: u8) -> VectorStats {
+    let handlers = match vector.checked_sub(FIRST_VECTOR) {
+        Some(index) if usize::from(index) < VECTORS => TABLE.lock().chains[usize::from(index)]
+            .iter()
+            .flatten()
+            .count(),
+        _ => 0,
+    };
+    VectorStats {
+        count: COUNTS[usize::from(vector)].load(Ordering::Relaxed),
+        unhandled: UNHANDLED[usize::from(vector)].load(Ordering::Relaxed),
+        handlers,
+    }
+}
+
+pub fn print_stats() {
+    for vector in 0..=u8::MAX {
+        let stats = stats(vector);
+        if stats.count == 0 && stats.handlers == 0 {
+            continue;
+        }
+        println!(
+            "{:#04x}: {} interrupts, {} unhandled",
+            vector, stats.count, stats.unhandled
+        );
+        if let Some(index) = vector.checked_sub(FIRST_VECTOR) {
+            if usize::from(index) < VECTORS {
+                let chain = TABLE.lock().chains[usize::from(index)];
+                for entry in chain.iter().flatten() {
+                    println!("    {}", entry.name);
+                }
+            }
+        }
+    }
+}
+
+// For the vectors with fixed handlers.
+pub(crate) fn count(vector: u8) {
+    COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+fn dispatch(vector: u8) {
+    count(vector);
+    // A copy, so handlers can register and unregister.
+    let chain = TABLE.lock().chains[chain_index(vector)];
+    let mut handled = false;
+    for entry in chain.iter().flatten() {
+        if (entry.handler)(entry.data) == IrqReturn::Handled {
+            handled = true;
+        }
+    }
+    if !handled {
+        UNHANDLED[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+    }
+    super::end_of_interrupt_vector(vector);
+}
+
+// The PICs raise IRQ 7 and 15 for interrupts that went away before they were
+// acknowledged. Those have no in-service bit and must not get an EOI at the
+// PIC that raised them, but a spurious IRQ 15 still came through the master.
+fn pic_spurious(vector: u8) -> bool {
+    let command_port = match isa_irq(vector) {
+        Some(7) => 0x20,
+        Some(15) => 0xa0,
+        _ => return false,
+    };
+    // Only the PICs do this, the I/O APIC delivers real interrupts only.
+    if apic::local_apic().is_some() {
+        return false;
+    }
+    let _pics = PICS.lock();
+    let mut command: Port<u8> = Port::new(command_port);
+    let in_service = unsafe {
+        command.write(PIC_READ_ISR);
+        command.read()
+    };
+    if in_service & 1 << 7 != 0 {
+        return false;
+    }
+    if command_port == 0xa0 {
+        unsafe { Port::<u8>::new(0x20).write(PIC_EOI) };
+    }
+    true
+}
+
+extern "x86-interrupt" fn dispatcher<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    let _irq = percpu::InterruptGuard::enter();
+    if pic_spurious(VECTOR) {
+        count(VECTOR);
+        return;
+    }
+    dispatch(VECTOR);
+}
+
+macro_rules! set_dispatchers {
+    ($idt:ident, $($vector:literal),*) => {
+        $($idt[$vector].set_handler_fn(dispatcher::<$vector>);)*
+    };
+}
+
+// Points every vector that handlers can be registered for at its dispatcher.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    set_dispatchers!(
+        idt, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2d, 0x2e,
+        0x2f, 0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d,
+        0x3e, 0x3f
+    );
+}
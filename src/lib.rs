@@ -9,12 +9,17 @@
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
 pub mod gdt;
 pub mod graphic;
 pub mod interrupts;
 pub mod memory;
+pub mod percpu;
+pub mod power;
+pub mod rtc;
 pub mod serial;
+pub mod sync;
 pub mod timer;
 //pub mod vga_buffer;
 
@@ -59,8 +64,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("{}", info);
+    serial::force_print(format_args!("[failed]\n\n{}\n", info));
     exit_qemu(QemuExitCode::Failed);
     loop {}
 }
@@ -76,10 +80,26 @@ pub fn init() {
     let mut port = Port::new(0x64);
     unsafe { port.write(0xd4 as u8) };
     gdt::init();
+    memory::mmio::init_pat();
+    memory::protect::init();
+    let cpu = percpu::init();
+    crate::println_info!("CPU {} online, APIC id {}.", cpu.cpu_id(), cpu.apic_id());
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() }
+    timer::init(timer::DEFAULT_FREQUENCY).expect("Programming the PIT failed.");
+    if let Some(frequency) = timer::tsc::init() {
+        crate::println_info!(
+            "TSC runs at {} MHz{}.",
+            frequency / 1_000_000,
+            if timer::tsc::invariant() {
+                ", invariant"
+            } else {
+                ""
+            }
+        );
+    }
     x86_64::instructions::interrupts::enable();
-    unsafe { interrupts::PICS.lock().write_masks(0x00, 0x00) };
+    interrupts::irq::enable_lines().expect("Unmasking interrupts failed.");
 }
 
 #[cfg(test)]
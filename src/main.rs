@@ -9,7 +9,7 @@ extern crate alloc;
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use osh1mc::{print, println};
+use osh1mc::{print, println, println_info};
 use vga::writers::GraphicsWriter;
 
 entry_point!(kernel_main);
@@ -17,7 +17,7 @@ entry_point!(kernel_main);
 #[no_mangle]
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use osh1mc::memory;
-    use osh1mc::memory::BootInfoFrameAllocator;
+    use osh1mc::memory::bitmap::BitmapFrameAllocator;
     use x86_64::VirtAddr;
 
     use osh1mc::allocator;
@@ -25,9 +25,36 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     osh1mc::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
     osh1mc::graphic::init_graphics();
+    println_info!(
+        "Frame allocator initialized. {} of {} frames free.",
+        frame_allocator.free_frames(),
+        frame_allocator.total_frames()
+    );
+    memory::install(mapper, frame_allocator);
+    osh1mc::acpi::print_summary();
+    println_info!("RTC initialized, it is {}.", osh1mc::rtc::init());
+    osh1mc::gdt::init_stacks().expect("IST stack allocation failed.");
+    memory::stack::register_current_stack("boot").expect("boot stack registration failed.");
+    match osh1mc::timer::init_hpet() {
+        Ok(()) => println_info!("HPET initialized."),
+        Err(error) => println_info!("No usable HPET ({:?}), the clock uses the PIT.", error),
+    }
+    if let Err(error) = osh1mc::interrupts::init_apic() {
+        println_info!("No usable APIC ({:?}), using the 8259 PICs.", error);
+    }
+    if osh1mc::timer::hpet_ticks() {
+        println_info!("The HPET drives the timer interrupt.");
+    }
+    memory::protect::protect_kernel_image(&boot_info.memory_map)
+        .expect("kernel image protection failed.");
+    println_info!(
+        "Kernel memory protected. {:?}",
+        memory::protect::supported_features()
+    );
     println!("");
     osh1mc::graphic::TEXT_WRITER.lock().set_color(0x03, 0x16);
     println!("Hello World! {}", 123);
@@ -79,10 +106,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     #[cfg(test)]
     test_main();
 
-    println!(
-        "Timer: {} sec",
-        osh1mc::timer::TIMER.lock().get() as f64 / 10.0
-    );
+    println!("Uptime: {:.2} sec", osh1mc::timer::uptime().as_secs_f64());
     println!("It did not crash!");
     osh1mc::hlt_loop();
 }
@@ -90,9 +114,19 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
     use osh1mc::graphic::TEXT_WRITER;
-    TEXT_WRITER.lock().set_color(0x01, 0xff);
-    println!("{}", info);
+    // The panicking code might hold the writer, e.g. on recursive locking.
+    match TEXT_WRITER.try_lock() {
+        Some(mut writer) => {
+            writer.set_color(0x01, 0xff);
+            let _ = writeln!(writer, "{}", info);
+        }
+        None => osh1mc::serial::force_print(format_args!("{}\n", info)),
+    }
+    if osh1mc::power::reboot_on_panic() {
+        osh1mc::power::reboot();
+    }
     osh1mc::hlt_loop();
 }
 
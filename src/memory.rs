@@ -1,10 +1,26 @@
+use bitmap::BitmapFrameAllocator;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
-use x86_64::structures::paging::{OffsetPageTable, PageTable};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{OffsetPageTable, PageTable, PageTableFlags};
 use x86_64::{
-    structures::paging::{FrameAllocator, Mapper, Page, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, Mapper, Page, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+pub mod address_space;
+pub mod bitmap;
+pub mod buddy;
+pub mod cow;
+pub mod fault;
+pub mod mmio;
+pub mod protect;
+pub mod stack;
+pub mod vmm;
+pub mod walk;
+
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
     let (level_4_table_frame, _) = Cr3::read();
@@ -14,14 +30,75 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::control::Cr3;
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (level_4_table_frame, _) = Cr3::read();
+    let kernel_table = level_4_table_frame.start_address().as_u64();
+    KERNEL_LEVEL_4_TABLE.store(kernel_table, Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
-fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
+// Offset of the complete physical memory mapping passed to `init`.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+// Frame of the level 4 table that was active during `init`.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed)))
+}
+
+// Address of `addr` in the complete physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+// The kernel's page table and frame allocator for code that can't get them
+// passed in, like the heap growing on demand. Set by `install`.
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+pub fn install(mapper: OffsetPageTable<'static>, mut frame_allocator: BitmapFrameAllocator) {
+    address_space::init_kernel_entries(&mut frame_allocator);
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappedSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappedSize::Size4KiB => Size4KiB::SIZE,
+            MappedSize::Size2MiB => Size2MiB::SIZE,
+            MappedSize::Size1GiB => Size1GiB::SIZE,
+        }
+    }
+}
+
+// Result of a successful translation.
+// `flags` are the effective flags of the whole walk: WRITABLE and
+// USER_ACCESSIBLE are only set if every level allows them, NO_EXECUTE is set
+// if any level forbids execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub addr: PhysAddr,
+    pub size: MappedSize,
+    pub flags: PageTableFlags,
+}
+
+fn translate_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<Translation> {
     use x86_64::registers::control::Cr3;
-    use x86_64::structures::paging::page_table::FrameError;
 
     let (level_4_table_frame, _) = Cr3::read();
     let table_indexes = [
@@ -30,25 +107,53 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         addr.p2_index(),
         addr.p1_index(),
     ];
-    let mut frame = level_4_table_frame;
+    let mut table_addr = level_4_table_frame.start_address();
+    let mut flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
 
-    for &index in &table_indexes {
-        let virt = physical_memory_offset + frame.start_address().as_u64();
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let virt = physical_memory_offset + table_addr.as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
         let table = unsafe { &*table_ptr };
 
         let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        flags = effective_flags(flags, entry.flags());
+        let size = match level {
+            1 if entry.flags().contains(PageTableFlags::HUGE_PAGE) => MappedSize::Size1GiB,
+            2 if entry.flags().contains(PageTableFlags::HUGE_PAGE) => MappedSize::Size2MiB,
+            3 => MappedSize::Size4KiB,
+            _ => {
+                table_addr = entry.addr();
+                continue;
+            }
         };
+        // Bit 12 of a huge page entry is its PAT bit, not part of the address.
+        let frame = entry.addr().align_down(size.bytes());
+        return Some(Translation {
+            addr: frame + (addr.as_u64() & (size.bytes() - 1)),
+            size,
+            flags,
+        });
     }
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+    unreachable!()
+}
+
+// Combines the flags collected so far with the flags of the next entry of a walk.
+pub(crate) fn effective_flags(upper: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut flags = (entry - inherited) | (entry & upper & inherited);
+    flags |= upper & PageTableFlags::NO_EXECUTE;
+    flags
+}
+
+pub unsafe fn translate(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<Translation> {
+    translate_inner(addr, physical_memory_offset)
 }
 
 pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-    translate_addr_inner(addr, physical_memory_offset)
+    translate_inner(addr, physical_memory_offset).map(|translation| translation.addr)
 }
 
 pub struct EmptyFrameAllocator;
@@ -0,0 +1,305 @@
+use super::cow::{self, CowError};
+use super::{kernel_level_4_frame, phys_to_virt, physical_memory_offset, FRAME_ALLOCATOR};
+use alloc::vec::Vec;
+use spin::Once;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    OutOfMemory,
+    // The page is in a level 4 entry shared with the kernel.
+    KernelAddress,
+    AlreadyMapped,
+    NotMapped,
+    // Huge pages can't be shared copy-on-write.
+    HugePage,
+}
+
+// Level 4 entries of the kernel's table that every address space shares, one
+// bit per entry. Set by `init_kernel_entries`.
+static KERNEL_ENTRIES: Once<[u64; 8]> = Once::new();
+
+// Gives every empty higher half level 4 entry of the kernel's table a level 3
+// table. Kernel mappings made later, like vmm regions, then never add level 4
+// entries, so address spaces see them without being updated.
+// The lower half entries that exist at this point are shared as well, since
+// the bootloader puts the kernel image, its stack and the physical memory
+// mapping there, and the heap lives there too. Mappings the kernel makes after
+// this have to stay in the shared entries, see `is_shared_range`.
+pub(super) fn init_kernel_entries(frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
+    let kernel_table: &mut PageTable =
+        unsafe { &mut *phys_to_virt(kernel_level_4_frame().start_address()).as_mut_ptr() };
+    let mut shared = [0; 8];
+    for index in 0..512 {
+        if index >= 256 && kernel_table[index].is_unused() {
+            let frame = frame_allocator
+                .allocate_frame()
+                .expect("out of memory for kernel page tables");
+            let table: &mut PageTable =
+                unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr() };
+            table.zero();
+            kernel_table[index]
+                .set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+        if !kernel_table[index].is_unused() {
+            shared[index / 64] |= 1 << (index % 64);
+        }
+    }
+    KERNEL_ENTRIES.call_once(|| shared);
+}
+
+// Whether `start..end` only uses level 4 entries that every address space
+// shares with the kernel. Before `memory::install` only the higher half counts,
+// since lower half entries that don't exist by then aren't shared.
+pub fn is_shared_range(start: VirtAddr, end: VirtAddr) -> bool {
+    let first = usize::from(start.p4_index());
+    let last = usize::from((end - 1u64).p4_index());
+    match KERNEL_ENTRIES.r#try() {
+        Some(shared) => (first..=last).all(|index| shared[index / 64] & (1 << (index % 64)) != 0),
+        None => first >= 256,
+    }
+}
+
+// An address space with its own level 4 table. The kernel's entries are
+// shared, see `init_kernel_entries`, everything else belongs to the address
+// space and is freed on drop.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    // One bit per level 4 entry that is shared with the kernel.
+    shared: [u64; 8],
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let frame = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .and_then(|frame_allocator| frame_allocator.allocate_frame())
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        let shared = *KERNEL_ENTRIES
+            .r#try()
+            .expect("memory::install has not been called");
+        let mut address_space = AddressSpace {
+            level_4_frame: frame,
+            shared,
+        };
+        let kernel_table: &PageTable =
+            unsafe { &*phys_to_virt(kernel_level_4_frame().start_address()).as_ptr() };
+        let table = address_space.table();
+        table.zero();
+        for index in 0..512 {
+            if shared[index / 64] & (1 << (index % 64)) != 0 {
+                table[index] = kernel_table[index].clone();
+            }
+        }
+        Ok(address_space)
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn table(&mut self) -> &mut PageTable {
+        let virt = phys_to_virt(self.level_4_frame.start_address());
+        unsafe { &mut *virt.as_mut_ptr() }
+    }
+
+    fn is_shared(&self, index: usize) -> bool {
+        self.shared[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    // A mapper for this address space, which doesn't need to be active.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let offset = physical_memory_offset();
+        unsafe { OffsetPageTable::new(self.table(), offset) }
+    }
+
+    // Maps `page` to a fresh zeroed frame that is freed with the address space.
+    pub fn map_user(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, AddressSpaceError> {
+        if self.is_shared(usize::from(page.p4_index())) {
+            return Err(AddressSpaceError::KernelAddress);
+        }
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator
+            .as_mut()
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        let frame_ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        unsafe { core::ptr::write_bytes(frame_ptr, 0, 4096) };
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let result = unsafe { self.mapper().map_to(page, frame, flags, frame_allocator) };
+        match result {
+            // Only flush if the mapping is visible right now.
+            Ok(flush) if self.is_active() => flush.flush(),
+            Ok(flush) => flush.ignore(),
+            Err(error) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(match error {
+                    MapToError::FrameAllocationFailed => AddressSpaceError::OutOfMemory,
+                    _ => AddressSpaceError::AlreadyMapped,
+                });
+            }
+        }
+        Ok(frame)
+    }
+
+    // Unmaps a page mapped with `map_user` and frees its frame.
+    pub fn unmap_user(&mut self, page: Page<Size4KiB>) -> Result<(), AddressSpaceError> {
+        if self.is_shared(usize::from(page.p4_index())) {
+            return Err(AddressSpaceError::KernelAddress);
+        }
+        let active = self.is_active();
+        let (frame, flush) = self
+            .mapper()
+            .unmap(page)
+            .map_err(|_| AddressSpaceError::NotMapped)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        if let Some(frame_allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            cow::release_frame(frame, frame_allocator);
+        }
+        Ok(())
+    }
+
+    // Creates a copy of this address space that shares all frames with it.
+    // Writable pages become copy-on-write in both, so the first write to a
+    // page gives the writer a private copy. Fails if there are huge pages.
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, AddressSpaceError> {
+        let mut pages = Vec::new();
+        for index in 0..512 {
+            if self.is_shared(index) || self.table()[index].is_unused() {
+                continue;
+            }
+            let frame = PhysFrame::containing_address(self.table()[index].addr());
+            let base = (index as u64) << 39;
+            unsafe { collect_pages(frame, 3, base, &mut pages)? };
+        }
+        let mut child = AddressSpace::new()?;
+        let mut mapper = self.mapper();
+        for page in pages {
+            cow::share_page(&mut mapper, page, &mut child.mapper(), page).map_err(|error| {
+                match error {
+                    CowError::OutOfMemory => AddressSpaceError::OutOfMemory,
+                    CowError::AlreadyMapped => AddressSpaceError::AlreadyMapped,
+                    CowError::HugePage => AddressSpaceError::HugePage,
+                    _ => AddressSpaceError::NotMapped,
+                }
+            })?;
+        }
+        Ok(child)
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    // Loads the address space into Cr3.
+    // Kernel code and data stay mapped, since their entries are shared.
+    pub unsafe fn switch(&mut self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    // Loads the kernel's own level 4 table into Cr3.
+    pub unsafe fn switch_to_kernel() {
+        Cr3::write(kernel_level_4_frame(), Cr3Flags::empty());
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe { AddressSpace::switch_to_kernel() };
+        }
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = match frame_allocator.as_mut() {
+            Some(frame_allocator) => frame_allocator,
+            None => return,
+        };
+        for index in 0..512 {
+            if self.is_shared(index) {
+                continue;
+            }
+            let entry = &self.table()[index];
+            if !entry.is_unused() {
+                let frame = PhysFrame::containing_address(entry.addr());
+                unsafe { free_table(frame, 3, frame_allocator) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+// Frees the page table in `frame` at the given level, all tables below it and
+// the frames they map.
+unsafe fn free_table(
+    frame: PhysFrame,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let table: &PageTable = &*phys_to_virt(frame.start_address()).as_ptr();
+    for entry in table.iter() {
+        if entry.is_unused() {
+            continue;
+        }
+        if level == 1 {
+            cow::release_frame(PhysFrame::containing_address(entry.addr()), frame_allocator);
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // Only created by someone else's mapper, so the frames aren't ours.
+            continue;
+        } else {
+            free_table(
+                PhysFrame::containing_address(entry.addr()),
+                level - 1,
+                frame_allocator,
+            );
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+// Collects the 4KiB pages mapped below the page table in `frame`, whose first
+// entry maps `base`.
+unsafe fn collect_pages(
+    frame: PhysFrame,
+    level: u8,
+    base: u64,
+    pages: &mut Vec<Page<Size4KiB>>,
+) -> Result<(), AddressSpaceError> {
+    let table: &PageTable = &*phys_to_virt(frame.start_address()).as_ptr();
+    for (index, entry) in table.iter().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let addr = base + ((index as u64) << (12 + 9 * (level as u64 - 1)));
+        if level == 1 {
+            // Sign extend, since the address might be in the higher half.
+            let addr = VirtAddr::new_truncate(addr);
+            pages.push(Page::containing_address(addr));
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Err(AddressSpaceError::HugePage);
+        } else {
+            collect_pages(
+                PhysFrame::containing_address(entry.addr()),
+                level - 1,
+                addr,
+                pages,
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+// Tracks every physical frame below the highest usable address with one bit.
+// A set bit means the frame is used (or not usable at all).
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    free_frames: usize,
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    // The passed memory map must be valid and the complete physical memory
+    // must be mapped at `physical_memory_offset`.
+    // All frames that are marked as 'USABLE' must be unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_size = (words * 8) as u64;
+        let bitmap_frames = (bitmap_size + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // The bitmap itself lives in the first usable region that is large enough.
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_frames * FRAME_SIZE)
+            .expect("no usable region is large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_addr();
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            free_frames: 0,
+            next: 0,
+        };
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.mark_free(index);
+            }
+        }
+        let bitmap_start_index = (bitmap_start / FRAME_SIZE) as usize;
+        for index in bitmap_start_index..bitmap_start_index + bitmap_frames as usize {
+            allocator.mark_used(index);
+        }
+        // Never hand out the frame at physical address zero.
+        allocator.mark_used(0);
+        allocator
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.frame_count - self.free_frames
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.frame_count
+    }
+
+    // Allocates `count` physically contiguous frames whose first frame is
+    // aligned to `align` frames. Useful for DMA buffers.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        if count == 0 || align == 0 || count > self.free_frames {
+            return None;
+        }
+        let mut start = 0;
+        while start + count <= self.frame_count {
+            match (start..start + count).find(|&index| self.is_used(index)) {
+                Some(used) => start = align_up(used + 1, align),
+                None => {
+                    for index in start..start + count {
+                        self.mark_used(index);
+                    }
+                    let first = frame_at(start);
+                    return Some(PhysFrame::range(first, first + count as u64));
+                }
+            }
+        }
+        None
+    }
+
+    // The frames must have been allocated by this allocator and be unused.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        if !self.is_used(index) {
+            self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+            self.free_frames -= 1;
+        }
+    }
+
+    fn mark_free(&mut self, index: usize) {
+        if self.is_used(index) {
+            self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+            self.free_frames += 1;
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+        let words = self.bitmap.len();
+        let first_word = self.next / BITS_PER_WORD;
+        for offset in 0..words {
+            let word_index = (first_word + offset) % words;
+            let word = self.bitmap[word_index];
+            if word == !0 {
+                continue;
+            }
+            let index = word_index * BITS_PER_WORD + word.trailing_ones() as usize;
+            if index >= self.frame_count {
+                continue;
+            }
+            self.mark_used(index);
+            self.next = index + 1;
+            return Some(frame_at(index));
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(
+            index < self.frame_count && self.is_used(index),
+            "deallocated frame {:?} is not allocated",
+            frame
+        );
+        self.mark_free(index);
+        if index < self.next {
+            self.next = index;
+        }
+    }
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
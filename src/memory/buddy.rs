@@ -0,0 +1,203 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+// Order 0 is a single 4 KiB frame, order 9 a 2 MiB frame and order 18 a 1 GiB frame.
+pub const MAX_ORDER: usize = 18;
+const NOT_FREE: u8 = 0xff;
+const NIL: usize = usize::MAX;
+
+// Header written into the first frame of every free block.
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
+// Buddy-system allocator over the usable regions of the bootloader's memory map.
+// Free blocks are kept in intrusive doubly linked lists per order, and
+// `orders[i]` records the order of the free block starting at frame `i`
+// (or `NOT_FREE`), so a buddy can be found and unlinked in O(1).
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    orders: &'static mut [u8],
+    free_lists: [usize; MAX_ORDER + 1],
+    free_blocks: [usize; MAX_ORDER + 1],
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    // The passed memory map must be valid and the complete physical memory
+    // must be mapped at `physical_memory_offset`.
+    // All frames that are marked as 'USABLE' must be unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let metadata_frames = (frame_count as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // The order map lives in the first usable region that is large enough.
+        let metadata_region = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= metadata_frames * FRAME_SIZE)
+            .expect("no usable region is large enough for the buddy allocator");
+        let metadata_start = metadata_region.range.start_addr();
+        let metadata_end = metadata_start + metadata_frames * FRAME_SIZE;
+        let orders_ptr: *mut u8 = (physical_memory_offset + metadata_start).as_mut_ptr();
+        let orders = core::slice::from_raw_parts_mut(orders_ptr, frame_count);
+        for order in orders.iter_mut() {
+            *order = NOT_FREE;
+        }
+
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            orders,
+            free_lists: [NIL; MAX_ORDER + 1],
+            free_blocks: [0; MAX_ORDER + 1],
+            free_frames: 0,
+        };
+        for region in usable_regions() {
+            let mut start = region.range.start_addr();
+            let end = region.range.end_addr();
+            if start == metadata_start {
+                start = metadata_end;
+            }
+            // Never hand out the frame at physical address zero.
+            let mut index = ((start / FRAME_SIZE) as usize).max(1);
+            let end = (end / FRAME_SIZE) as usize;
+            while index < end {
+                let mut order = (index.trailing_zeros() as usize).min(MAX_ORDER);
+                while index + (1 << order) > end {
+                    order -= 1;
+                }
+                allocator.free_block(index, order);
+                index += 1 << order;
+            }
+        }
+        allocator
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.orders.len()
+    }
+
+    // Number of free blocks of the given order.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_blocks[order]
+    }
+
+    // Allocates `1 << order` physically contiguous frames aligned to their size.
+    pub fn allocate_order(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NIL)?;
+        let index = self.free_lists[current];
+        self.unlink(index, current);
+        // Split the block and give the upper halves back until it has the requested order.
+        while current > order {
+            current -= 1;
+            self.push(index + (1 << current), current);
+        }
+        self.free_frames -= 1 << order;
+        Some(frame_at(index))
+    }
+
+    // The block must have been allocated by `allocate_order` with the same order.
+    pub unsafe fn deallocate_order(&mut self, frame: PhysFrame, order: usize) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(
+            order <= MAX_ORDER && index % (1 << order) == 0 && index < self.orders.len(),
+            "invalid block {:?} of order {}",
+            frame,
+            order
+        );
+        assert!(
+            self.orders[index] == NOT_FREE,
+            "deallocated block {:?} is already free",
+            frame
+        );
+        self.free_block(index, order);
+    }
+
+    // Returns a block to the free lists and merges it with its buddies.
+    fn free_block(&mut self, mut index: usize, mut order: usize) {
+        self.free_frames += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if buddy >= self.orders.len() || self.orders[buddy] != order as u8 {
+                break;
+            }
+            self.unlink(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+
+    fn push(&mut self, index: usize, order: usize) {
+        let head = self.free_lists[order];
+        unsafe {
+            *self.block(index) = FreeBlock {
+                next: head,
+                prev: NIL,
+            };
+            if head != NIL {
+                (*self.block(head)).prev = index;
+            }
+        }
+        self.free_lists[order] = index;
+        self.orders[index] = order as u8;
+        self.free_blocks[order] += 1;
+    }
+
+    fn unlink(&mut self, index: usize, order: usize) {
+        let FreeBlock { next, prev } = unsafe { core::ptr::read(self.block(index)) };
+        if prev == NIL {
+            self.free_lists[order] = next;
+        } else {
+            unsafe { (*self.block(prev)).next = next };
+        }
+        if next != NIL {
+            unsafe { (*self.block(next)).prev = prev };
+        }
+        self.orders[index] = NOT_FREE;
+        self.free_blocks[order] -= 1;
+    }
+
+    fn block(&self, index: usize) -> *mut FreeBlock {
+        (self.physical_memory_offset + index as u64 * FRAME_SIZE).as_mut_ptr()
+    }
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn order_of<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE).trailing_zeros() as usize
+}
+
+unsafe impl<S: PageSize> FrameAllocator<S> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let frame = self.allocate_order(order_of::<S>())?;
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_order(frame, order_of::<S>());
+    }
+}
@@ -0,0 +1,246 @@
+use super::{phys_to_virt, physical_memory_offset, FRAME_ALLOCATOR};
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use x86_64::structures::paging::mapper::{MapToError, MappedFrame, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+// Marks a page that was writable before it got shared. Relies on CR0.WP,
+// set by `protect::init`, so writes of the kernel fault too.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CowError {
+    NotMapped,
+    HugePage,
+    AlreadyMapped,
+    OutOfMemory,
+    Busy,
+    // The frame's mapping count would overflow.
+    TooManyMappings,
+}
+
+// Number of mappings of every physical frame, indexed by frame number.
+// Zero means the frame isn't shared and has a single owner.
+// Lives in frames of its own, so it can be used from the page fault handler.
+static REFCOUNTS: AtomicUsize = AtomicUsize::new(0);
+static REFCOUNTS_LEN: AtomicUsize = AtomicUsize::new(0);
+
+fn refcounts() -> &'static [AtomicU16] {
+    let ptr = REFCOUNTS.load(Ordering::Acquire) as *const AtomicU16;
+    if ptr.is_null() {
+        return &[];
+    }
+    unsafe { core::slice::from_raw_parts(ptr, REFCOUNTS_LEN.load(Ordering::Relaxed)) }
+}
+
+fn init_refcounts(frame_allocator: &mut super::bitmap::BitmapFrameAllocator) -> Option<()> {
+    if !refcounts().is_empty() {
+        return Some(());
+    }
+    let len = frame_allocator.total_frames();
+    let frames = (len * 2 + 4095) / 4096;
+    let range = frame_allocator.allocate_contiguous(frames, 1)?;
+    let ptr: *mut u8 = phys_to_virt(range.start.start_address()).as_mut_ptr();
+    unsafe { core::ptr::write_bytes(ptr, 0, frames * 4096) };
+    REFCOUNTS_LEN.store(len, Ordering::Relaxed);
+    REFCOUNTS.store(ptr as usize, Ordering::Release);
+    Some(())
+}
+
+fn refcount(frame: PhysFrame) -> Option<&'static AtomicU16> {
+    refcounts().get((frame.start_address().as_u64() / 4096) as usize)
+}
+
+// Number of mappings of `frame`, 1 if it isn't shared.
+pub fn mapping_count(frame: PhysFrame) -> u16 {
+    refcount(frame)
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+        .max(1)
+}
+
+// Drops one mapping of `frame` and frees it with the last one.
+pub fn release_frame(frame: PhysFrame, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+    if let Some(count) = refcount(frame) {
+        let previous = count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                Some(count.saturating_sub(1))
+            })
+            .unwrap();
+        if previous > 1 {
+            return;
+        }
+    }
+    unsafe { frame_allocator.deallocate_frame(frame) };
+}
+
+// Makes `page` read-only and copy-on-write if it is writable, and counts one
+// more mapping of its frame.
+fn make_shared(
+    mapper: &mut OffsetPageTable,
+    page: Page<Size4KiB>,
+) -> Result<(PhysFrame, PageTableFlags), CowError> {
+    let (frame, mut flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        TranslateResult::Mapped { .. } => return Err(CowError::HugePage),
+        _ => return Err(CowError::NotMapped),
+    };
+    // Counted before the page becomes copy-on-write, so it never is without a count.
+    let count = refcount(frame).ok_or(CowError::OutOfMemory)?;
+    count
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+            count.max(1).checked_add(1)
+        })
+        .map_err(|_| CowError::TooManyMappings)?;
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+        match unsafe { mapper.update_flags(page, flags) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                count.fetch_sub(1, Ordering::AcqRel);
+                return Err(CowError::NotMapped);
+            }
+        }
+    }
+    Ok((frame, flags))
+}
+
+// Maps `src_page` of `src` at `dst_page` of `dst` sharing its frame.
+// Writable pages become copy-on-write in both.
+pub fn share_page(
+    src: &mut OffsetPageTable,
+    src_page: Page<Size4KiB>,
+    dst: &mut OffsetPageTable,
+    dst_page: Page<Size4KiB>,
+) -> Result<(), CowError> {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().ok_or(CowError::OutOfMemory)?;
+    init_refcounts(frame_allocator).ok_or(CowError::OutOfMemory)?;
+    let (frame, flags) = make_shared(src, src_page)?;
+    map_shared(dst, dst_page, frame, flags, frame_allocator)
+}
+
+// Maps `count` pages starting at `src` again at `dst` in the same page table,
+// e.g. to snapshot a buffer. Writable pages become copy-on-write in both places.
+pub fn snapshot_range(
+    mapper: &mut OffsetPageTable,
+    src: Page<Size4KiB>,
+    dst: Page<Size4KiB>,
+    count: u64,
+) -> Result<(), CowError> {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().ok_or(CowError::OutOfMemory)?;
+    init_refcounts(frame_allocator).ok_or(CowError::OutOfMemory)?;
+    for i in 0..count {
+        let (frame, flags) = make_shared(mapper, src + i)?;
+        map_shared(mapper, dst + i, frame, flags, frame_allocator)?;
+    }
+    Ok(())
+}
+
+fn map_shared(
+    mapper: &mut OffsetPageTable,
+    page: Page<Size4KiB>,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut super::bitmap::BitmapFrameAllocator,
+) -> Result<(), CowError> {
+    // The page itself is read-only, but the tables above it must not be, or
+    // it stays read-only once the write fault makes it writable again.
+    let table_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+    let result =
+        unsafe { mapper.map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator) };
+    match result {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(_) => {
+            release_frame(frame, frame_allocator);
+            Err(CowError::AlreadyMapped)
+        }
+    }
+}
+
+// Resolves a write to a copy-on-write page of the active address space.
+// Returns `None` if the page isn't copy-on-write. Called from the page fault
+// handler, so it doesn't wait for locks.
+pub fn handle_write_fault(addr: VirtAddr) -> Option<Result<(), CowError>> {
+    use x86_64::registers::control::Cr3;
+
+    let offset = physical_memory_offset();
+    let level_4_table: &mut PageTable =
+        unsafe { &mut *phys_to_virt(Cr3::read().0.start_address()).as_mut_ptr() };
+    let mut mapper = unsafe { OffsetPageTable::new(level_4_table, offset) };
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let (frame, flags) = match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+        _ => return None,
+    };
+    let writable = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    // The last mapping of a frame takes it over.
+    if mapping_count(frame) <= 1 {
+        if let Some(count) = refcount(frame) {
+            count.store(0, Ordering::Release);
+        }
+        let result = unsafe { mapper.update_flags(page, writable) };
+        return Some(match result {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(_) => Err(CowError::NotMapped),
+        });
+    }
+
+    let mut frame_allocator = match FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return Some(Err(CowError::Busy)),
+    };
+    let frame_allocator = match frame_allocator.as_mut() {
+        Some(frame_allocator) => frame_allocator,
+        None => return Some(Err(CowError::Busy)),
+    };
+    let copy = match frame_allocator.allocate_frame() {
+        Some(copy) => copy,
+        None => return Some(Err(CowError::OutOfMemory)),
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+            phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+            4096,
+        );
+    }
+    let result = mapper.unmap(page).map(|(_, flush)| flush.flush());
+    if result.is_err() {
+        unsafe { frame_allocator.deallocate_frame(copy) };
+        return Some(Err(CowError::NotMapped));
+    }
+    if let Err(error) = unsafe { mapper.map_to(page, copy, writable, frame_allocator) } {
+        // Put the shared frame back, its count still includes this mapping.
+        unsafe { frame_allocator.deallocate_frame(copy) };
+        if let Ok(flush) = unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            flush.flush();
+        }
+        return Some(Err(match error {
+            MapToError::FrameAllocationFailed => CowError::OutOfMemory,
+            _ => CowError::AlreadyMapped,
+        }));
+    }
+    release_frame(frame, frame_allocator);
+    Some(Ok(()))
+}
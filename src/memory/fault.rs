@@ -0,0 +1,182 @@
+use super::cow::{self, CowError};
+use super::protect::{self, Violation};
+use super::{phys_to_virt, FRAME_ALLOCATOR, MAPPER};
+use spin::Mutex;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+const MAX_REGIONS: usize = 64;
+
+// An anonymous region whose pages are backed by zero-filled frames on first touch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    // Exclusive.
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+}
+
+impl LazyRegion {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    Unaligned,
+    Overlapping,
+    TooManyRegions,
+    // Outside the level 4 entries address spaces share with the kernel, so
+    // the pages wouldn't show up in them.
+    NotShared,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    // The address is not part of any registered region.
+    NotRegistered,
+    // The address is in the guard page of the named stack.
+    StackOverflow(&'static str),
+    // The page is present, but the access is not allowed.
+    ProtectionViolation(Violation),
+    // A write to a region that is not writable.
+    ReadOnlyRegion(&'static str),
+    OutOfMemory,
+    MapFailed,
+    // The region list, page table or frame allocator was locked when the fault happened.
+    Busy,
+}
+
+// Registered regions don't live on the heap, so faults can be resolved
+// while the heap is locked.
+static REGIONS: Mutex<[Option<LazyRegion>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+// Registers `start..start + size` for demand paging. Its pages must not be mapped yet.
+pub fn register_lazy_region(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<LazyRegion, RegionError> {
+    if !start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 || size == 0 {
+        return Err(RegionError::Unaligned);
+    }
+    if !super::address_space::is_shared_range(start, start + size) {
+        return Err(RegionError::NotShared);
+    }
+    let region = LazyRegion {
+        name,
+        start,
+        end: start + size,
+        flags: flags | PageTableFlags::PRESENT,
+    };
+    let mut regions = REGIONS.lock();
+    if regions
+        .iter()
+        .flatten()
+        .any(|r| r.start < region.end && region.start < r.end)
+    {
+        return Err(RegionError::Overlapping);
+    }
+    let slot = regions
+        .iter_mut()
+        .find(|r| r.is_none())
+        .ok_or(RegionError::TooManyRegions)?;
+    *slot = Some(region);
+    Ok(region)
+}
+
+// Removes the region starting at `start`, unmaps its pages and frees their frames.
+pub fn unregister_region(start: VirtAddr) -> Option<LazyRegion> {
+    let region = {
+        let mut regions = REGIONS.lock();
+        let slot = regions
+            .iter_mut()
+            .find(|r| matches!(r, Some(r) if r.start == start))?;
+        slot.take()?
+    };
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    if let (Some(mapper), Some(frame_allocator)) = (mapper.as_mut(), frame_allocator.as_mut()) {
+        let first = Page::<Size4KiB>::containing_address(region.start);
+        let last = Page::containing_address(region.end - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                cow::release_frame(frame, frame_allocator);
+            }
+        }
+    }
+    Some(region)
+}
+
+// Returns the registered region containing `addr`.
+pub fn region_containing(addr: VirtAddr) -> Option<LazyRegion> {
+    REGIONS
+        .try_lock()?
+        .iter()
+        .flatten()
+        .find(|r| r.contains(addr))
+        .copied()
+}
+
+// Resolves a page fault at `addr` by backing the page with a zeroed frame.
+// Called from the page fault handler, so it must not allocate on the heap
+// and doesn't wait for locks.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    if let Some(name) = super::stack::guard_page_owner(addr) {
+        return Err(FaultError::StackOverflow(name));
+    }
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            match cow::handle_write_fault(addr) {
+                Some(Ok(())) => return Ok(()),
+                Some(Err(CowError::OutOfMemory)) => return Err(FaultError::OutOfMemory),
+                Some(Err(CowError::Busy)) => return Err(FaultError::Busy),
+                Some(Err(_)) => return Err(FaultError::MapFailed),
+                None => {}
+            }
+        }
+        return Err(FaultError::ProtectionViolation(protect::classify(
+            addr, error_code,
+        )));
+    }
+    let region = {
+        let regions = REGIONS.try_lock().ok_or(FaultError::Busy)?;
+        let region = regions.iter().flatten().find(|r| r.contains(addr)).copied();
+        region.ok_or(FaultError::NotRegistered)?
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return Err(FaultError::ReadOnlyRegion(region.name));
+    }
+
+    let mut mapper = MAPPER.try_lock().ok_or(FaultError::Busy)?;
+    let mut frame_allocator = FRAME_ALLOCATOR.try_lock().ok_or(FaultError::Busy)?;
+    let mapper = mapper.as_mut().ok_or(FaultError::Busy)?;
+    let frame_allocator = frame_allocator.as_mut().ok_or(FaultError::Busy)?;
+
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(FaultError::OutOfMemory)?;
+    let frame_ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe { core::ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize) };
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let flags = region.flags | protect::no_execute();
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(FaultError::MapFailed)
+        }
+    }
+}
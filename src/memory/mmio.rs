@@ -0,0 +1,177 @@
+use super::vmm::{self, VirtRegion, VmError};
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+const IA32_PAT: u32 = 0x277;
+const PAGE_SIZE: u64 = 4096;
+
+const PAT_WRITE_COMBINING: u64 = 0x01;
+
+// PAT index 2 (PCD) is uncached minus after reset, we use it for
+// write-combining. Uncached mappings use index 3 (PCD | PWT) instead.
+// The other entries keep their reset values.
+const WRITE_COMBINING_INDEX: u64 = 2;
+
+static WRITE_COMBINING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    WriteBack,
+    WriteThrough,
+    Uncached,
+    // Falls back to uncached if the CPU has no PAT.
+    WriteCombining,
+}
+
+impl CachePolicy {
+    // The page table flags that select the policy's PAT entry.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CachePolicy::WriteBack => PageTableFlags::empty(),
+            CachePolicy::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CachePolicy::WriteCombining if WRITE_COMBINING.load(Ordering::Relaxed) => {
+                PageTableFlags::NO_CACHE
+            }
+            CachePolicy::Uncached | CachePolicy::WriteCombining => {
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            }
+        }
+    }
+}
+
+fn has_pat() -> bool {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    cpuid.edx & (1 << 16) != 0
+}
+
+// Programs the write-combining entry of the PAT. Has to run once at boot,
+// before anything is mapped with `CachePolicy::WriteCombining`.
+pub fn init_pat() {
+    if !has_pat() {
+        return;
+    }
+    let mut msr = Msr::new(IA32_PAT);
+    unsafe {
+        let shift = WRITE_COMBINING_INDEX * 8;
+        let pat = msr.read() & !(0xff << shift);
+        msr.write(pat | PAT_WRITE_COMBINING << shift);
+        // Caches may hold lines with the old memory type.
+        core::arch::asm!("wbinvd", options(nostack));
+        x86_64::instructions::tlb::flush_all();
+    }
+    WRITE_COMBINING.store(true, Ordering::Relaxed);
+}
+
+// A range of device memory mapped into the kernel half. Unmapped on drop.
+pub struct MmioRegion {
+    region: VirtRegion,
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: usize,
+}
+
+impl MmioRegion {
+    // Maps `size` bytes of device memory at `phys`, which doesn't have to be
+    // page aligned.
+    pub fn map(
+        name: &'static str,
+        phys: PhysAddr,
+        size: usize,
+        policy: CachePolicy,
+    ) -> Result<Self, VmError> {
+        let start = phys.align_down(PAGE_SIZE);
+        let offset = phys - start;
+        let pages_size = (offset + size as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let flags = PageTableFlags::WRITABLE | policy.flags();
+        let region = vmm::map_phys(name, start, pages_size, flags)?;
+        Ok(MmioRegion {
+            region,
+            phys,
+            virt: region.start + offset,
+            size,
+        })
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.size,
+            "mmio access out of bounds"
+        );
+        assert!(
+            offset % core::mem::align_of::<T>() == 0,
+            "unaligned mmio access"
+        );
+        (self.virt + offset as u64).as_mut_ptr()
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let _ = vmm::free(&self.region);
+    }
+}
+
+// Device memory laid out as a `T`, e.g. a `#[repr(C)]` struct of registers.
+pub struct Mmio<T> {
+    region: MmioRegion,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> Mmio<T> {
+    pub fn map(name: &'static str, phys: PhysAddr, policy: CachePolicy) -> Result<Self, VmError> {
+        let region = MmioRegion::map(name, phys, core::mem::size_of::<T>(), policy)?;
+        Ok(Mmio {
+            region,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn read(&self) -> T {
+        self.region.read(0)
+    }
+
+    pub fn write(&self, value: T) {
+        self.region.write(0, value)
+    }
+
+    // Volatile access to a single register, e.g.
+    // `mmio.read_field(|r| unsafe { addr_of_mut!((*r).status) })`.
+    pub fn read_field<F: Copy>(&self, field: impl FnOnce(*mut T) -> *mut F) -> F {
+        unsafe { field(self.region.as_mut_ptr()).read_volatile() }
+    }
+
+    pub fn write_field<F: Copy>(&self, field: impl FnOnce(*mut T) -> *mut F, value: F) {
+        unsafe { field(self.region.as_mut_ptr()).write_volatile(value) }
+    }
+
+    pub fn region(&self) -> &MmioRegion {
+        &self.region
+    }
+}
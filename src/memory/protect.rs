@@ -0,0 +1,223 @@
+use super::{phys_to_virt, physical_memory_offset, MAPPER};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+static NO_EXECUTE: AtomicBool = AtomicBool::new(false);
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    pub no_execute: bool,
+    pub smep: bool,
+    pub smap: bool,
+}
+
+pub fn supported_features() -> Features {
+    let (max_extended, max_basic) = unsafe { (__cpuid(0x8000_0000).eax, __cpuid(0).eax) };
+    let no_execute =
+        max_extended >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0;
+    let leaf_7 = if max_basic >= 7 {
+        unsafe { core::arch::x86_64::__cpuid_count(7, 0) }.ebx
+    } else {
+        0
+    };
+    Features {
+        no_execute,
+        smep: leaf_7 & (1 << 7) != 0,
+        smap: leaf_7 & (1 << 20) != 0,
+    }
+}
+
+// Turns on write protection for the kernel and every protection feature the
+// CPU has. Must run before anything is mapped with `no_execute()`.
+pub fn init() -> Features {
+    let features = supported_features();
+    unsafe {
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT));
+        if features.no_execute {
+            Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        Cr4::update(|cr4| {
+            cr4.set(
+                Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION,
+                features.smep,
+            );
+            cr4.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, features.smap);
+        });
+    }
+    NO_EXECUTE.store(features.no_execute, Ordering::Relaxed);
+    SMAP.store(features.smap, Ordering::Relaxed);
+    features
+}
+
+// NO_EXECUTE if it is enabled. Setting it otherwise makes the entry invalid.
+pub fn no_execute() -> PageTableFlags {
+    if NO_EXECUTE.load(Ordering::Relaxed) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+// Runs `f` with SMAP lifted, so the kernel can access user pages.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = SMAP.load(Ordering::Relaxed);
+    if smap {
+        unsafe { core::arch::asm!("stac", options(nomem, nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { core::arch::asm!("clac", options(nomem, nostack)) };
+    }
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectError {
+    NoKernelImage,
+    NoMapper,
+}
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+#[derive(Clone, Copy)]
+struct Segment {
+    start: u64,
+    end: u64,
+    flags: u32,
+}
+
+// The loadable segments of the kernel ELF file the bootloader left in memory.
+fn kernel_segments(memory_map: &MemoryMap) -> Option<impl Iterator<Item = Segment>> {
+    let image = memory_map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Kernel)
+        .map(|r| phys_to_virt(x86_64::PhysAddr::new(r.range.start_addr())))
+        .find(|virt| unsafe { *virt.as_ptr::<[u8; 4]>() } == *b"\x7fELF")?;
+    let read_u16 = move |offset: u64| unsafe { (image + offset).as_ptr::<u16>().read_unaligned() };
+    let read_u32 = move |offset: u64| unsafe { (image + offset).as_ptr::<u32>().read_unaligned() };
+    let read_u64 = move |offset: u64| unsafe { (image + offset).as_ptr::<u64>().read_unaligned() };
+    let phoff = read_u64(0x20);
+    let phentsize = u64::from(read_u16(0x36));
+    let phnum = u64::from(read_u16(0x38));
+    let segments = (0..phnum)
+        .map(move |i| phoff + i * phentsize)
+        .filter(move |&header| read_u32(header) == PT_LOAD)
+        .map(move |header| {
+            let start = read_u64(header + 0x10);
+            Segment {
+                start,
+                end: start + read_u64(header + 0x28),
+                flags: read_u32(header + 4),
+            }
+        });
+    Some(segments)
+}
+
+// Makes the kernel's code read-only and everything else in the kernel image
+// non-executable, following the ELF segment flags. A page shared by two
+// segments keeps the permissions of both.
+pub fn protect_kernel_image(memory_map: &MemoryMap) -> Result<(), ProtectError> {
+    let segments = || kernel_segments(memory_map).ok_or(ProtectError::NoKernelImage);
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or(ProtectError::NoMapper)?;
+    for segment in segments()? {
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.start));
+        let last = Page::containing_address(VirtAddr::new(segment.end - 1));
+        for page in Page::range_inclusive(first, last) {
+            let page_start = page.start_address().as_u64();
+            let page_end = page_start + 4096;
+            let flags = segments()?
+                .filter(|s| s.start < page_end && page_start < s.end)
+                .fold(0, |flags, s| flags | s.flags);
+            let mut remove = PageTableFlags::empty();
+            let mut insert = PageTableFlags::empty();
+            if flags & PF_W == 0 {
+                remove |= PageTableFlags::WRITABLE;
+            }
+            if flags & PF_X == 0 {
+                insert |= no_execute();
+            }
+            update_flags(mapper, page, remove, insert);
+        }
+    }
+    Ok(())
+}
+
+// Marks the mapped 4KiB pages in `start..end` non-executable.
+pub fn mark_no_execute(start: VirtAddr, end: VirtAddr) -> Result<(), ProtectError> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or(ProtectError::NoMapper)?;
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::containing_address(end - 1u64);
+    for page in Page::range_inclusive(first, last) {
+        update_flags(mapper, page, PageTableFlags::empty(), no_execute());
+    }
+    Ok(())
+}
+
+fn update_flags(
+    mapper: &mut OffsetPageTable,
+    page: Page<Size4KiB>,
+    remove: PageTableFlags,
+    insert: PageTableFlags,
+) {
+    if let TranslateResult::Mapped {
+        frame: MappedFrame::Size4KiB(_),
+        flags,
+        ..
+    } = mapper.translate(page.start_address())
+    {
+        let flags = (flags - remove) | insert;
+        if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+            flush.flush();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    // Instruction fetch from a NO_EXECUTE page.
+    NoExecute,
+    // Write to a page that isn't writable.
+    ReadOnly,
+    // The kernel executed a user page.
+    Smep,
+    // The kernel accessed a user page outside `with_user_access`.
+    Smap,
+    // A user mode access to a kernel page.
+    KernelPage,
+}
+
+// Explains a page fault with PROTECTION_VIOLATION set at `addr` in the
+// active address space.
+pub fn classify(addr: VirtAddr, error_code: PageFaultErrorCode) -> Violation {
+    let flags = unsafe { super::translate(addr, physical_memory_offset()) }
+        .map_or(PageTableFlags::empty(), |translation| translation.flags);
+    let user_page = flags.contains(PageTableFlags::USER_ACCESSIBLE);
+    let user_mode = error_code.contains(PageFaultErrorCode::USER_MODE);
+    if user_mode && !user_page {
+        Violation::KernelPage
+    } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        if !user_mode && user_page && !flags.contains(PageTableFlags::NO_EXECUTE) {
+            Violation::Smep
+        } else {
+            Violation::NoExecute
+        }
+    } else if !user_mode && user_page && SMAP.load(Ordering::Relaxed) {
+        Violation::Smap
+    } else {
+        Violation::ReadOnly
+    }
+}
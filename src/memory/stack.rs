@@ -0,0 +1,96 @@
+use super::protect;
+use super::vmm::{self, VirtRegion, VmError};
+use super::{physical_memory_offset, translate_addr};
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = 4096;
+// Give up looking for the bottom of the current stack after this many pages.
+const MAX_STACK_PAGES: u64 = 1024;
+
+// A kernel stack with an unmapped guard page below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    region: VirtRegion,
+}
+
+impl KernelStack {
+    pub fn name(&self) -> &'static str {
+        self.region.name
+    }
+
+    // The initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.region.end()
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.region.start + self.region.guard
+    }
+
+    pub fn guard_page(&self) -> Page<Size4KiB> {
+        Page::containing_address(self.region.start)
+    }
+}
+
+// Allocates a stack of `size` bytes with a guard page below it.
+pub fn allocate(name: &'static str, size: u64) -> Result<KernelStack, VmError> {
+    let size = (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let region = vmm::reserve_with_guard(name, size, PAGE_SIZE)?;
+    let flags = PageTableFlags::WRITABLE;
+    match vmm::map(&region, flags) {
+        Ok(region) => Ok(KernelStack { region }),
+        Err(error) => {
+            let _ = vmm::release(&region);
+            Err(error)
+        }
+    }
+}
+
+// Unmaps a stack that is no longer in use.
+pub fn free(stack: KernelStack) -> Result<(), VmError> {
+    vmm::free(&stack.region)
+}
+
+// Registers the stack we are running on, which was set up by the bootloader,
+// so overflows of it are reported. The stack is assumed to be the mapped
+// pages around the stack pointer, and its guard the unmapped page below them.
+pub fn register_current_stack(name: &'static str) -> Result<KernelStack, VmError> {
+    let translate = |addr: VirtAddr| unsafe { translate_addr(addr, physical_memory_offset()) };
+    let rsp = VirtAddr::from_ptr(&name as *const _);
+    let mut bottom = rsp.align_down(PAGE_SIZE);
+    let mut top = bottom + PAGE_SIZE;
+    let mut pages = 1;
+    while translate(bottom - PAGE_SIZE).is_some() {
+        bottom -= PAGE_SIZE;
+        pages += 1;
+        if pages == MAX_STACK_PAGES {
+            return Err(VmError::OutOfAddressSpace);
+        }
+    }
+    while pages < MAX_STACK_PAGES && translate(top).is_some() {
+        top += PAGE_SIZE;
+        pages += 1;
+    }
+    // Mapped by the bootloader, so the region is only reserved.
+    let guard = bottom - PAGE_SIZE;
+    let region = vmm::reserve_at_with_guard(name, guard, top - guard, PAGE_SIZE)?;
+    let _ = protect::mark_no_execute(bottom, top);
+    Ok(KernelStack { region })
+}
+
+// Returns the name of the stack whose guard page contains `addr`.
+// Doesn't wait for locks, so it can be used from fault handlers.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+    vmm::region_containing(addr)
+        .filter(|region| region.is_guard(addr))
+        .map(|region| region.name)
+}
+
+// The bottom and top of the registered stack containing `addr`.
+// Doesn't wait for locks, so it can be used from the allocator.
+pub fn bounds(addr: VirtAddr) -> Option<(VirtAddr, VirtAddr)> {
+    vmm::region_containing(addr)
+        .filter(|region| !region.is_guard(addr))
+        .map(|region| (region.start + region.guard, region.end()))
+}
@@ -0,0 +1,406 @@
+use super::{cow, fault, protect, FRAME_ALLOCATOR, MAPPER};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Regions without a fixed address are placed in this part of the kernel half.
+pub const KERNEL_SPACE_START: u64 = 0xffff_c000_0000_0000;
+pub const KERNEL_SPACE_END: u64 = 0xffff_e000_0000_0000;
+const MAX_REGIONS: usize = 128;
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    // Reserved, but nothing is mapped.
+    Unmapped,
+    // Mapped to frames from the frame allocator, which are freed with the region.
+    Allocated,
+    // Mapped to frames owned by someone else, like device memory.
+    Physical(PhysAddr),
+    // Backed on first touch by the page fault handler.
+    Lazy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    // Includes the guard.
+    pub size: u64,
+    // Bytes at the start of the region that are never mapped, so overflows of
+    // a stack growing down into them fault.
+    pub guard: u64,
+    pub backing: Backing,
+}
+
+impl VirtRegion {
+    // Exclusive.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    pub fn is_guard(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.start + self.guard
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start.as_u64() < end && start < self.end().as_u64()
+    }
+
+    // The pages that can be mapped, everything but the guard.
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let first = Page::containing_address(self.start + self.guard);
+        Page::range(first, first + (self.size - self.guard) / PAGE_SIZE)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    Unaligned,
+    Overlapping,
+    OutOfAddressSpace,
+    TooManyRegions,
+    NotReserved,
+    AlreadyMapped,
+    OutOfMemory,
+    MapFailed,
+}
+
+// The bookkeeping doesn't use the heap, so regions can be reserved before
+// the heap exists and while it is growing.
+static REGIONS: Mutex<[Option<VirtRegion>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+// Reserves `size` bytes aligned to `align` in the kernel half.
+pub fn reserve(name: &'static str, size: u64, align: u64) -> Result<VirtRegion, VmError> {
+    reserve_inner(name, size, align, 0)
+}
+
+// Reserves `size` bytes in the kernel half, preceded by `guard` bytes that are never mapped.
+pub fn reserve_with_guard(
+    name: &'static str,
+    size: u64,
+    guard: u64,
+) -> Result<VirtRegion, VmError> {
+    reserve_inner(name, guard + size, PAGE_SIZE, guard)
+}
+
+fn reserve_inner(
+    name: &'static str,
+    size: u64,
+    align: u64,
+    guard: u64,
+) -> Result<VirtRegion, VmError> {
+    let aligned = size % PAGE_SIZE == 0 && guard % PAGE_SIZE == 0 && align.is_power_of_two();
+    if !aligned || guard >= size {
+        return Err(VmError::Unaligned);
+    }
+    let align = align.max(PAGE_SIZE);
+    let mut regions = REGIONS.lock();
+    let mut start = KERNEL_SPACE_START;
+    // First fit: skip past every region in the way until nothing overlaps.
+    while let Some(region) = regions
+        .iter()
+        .flatten()
+        .find(|r| r.overlaps(start, start + size))
+    {
+        start = align_up(region.end().as_u64(), align);
+        if start + size > KERNEL_SPACE_END {
+            return Err(VmError::OutOfAddressSpace);
+        }
+    }
+    insert(&mut regions, name, start, size, guard)
+}
+
+// Reserves `start..start + size` at a fixed address.
+pub fn reserve_at(name: &'static str, start: VirtAddr, size: u64) -> Result<VirtRegion, VmError> {
+    reserve_at_with_guard(name, start, size, 0)
+}
+
+// Reserves `start..start + size` at a fixed address, the first `guard` bytes
+// of which are never mapped.
+pub fn reserve_at_with_guard(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    guard: u64,
+) -> Result<VirtRegion, VmError> {
+    let aligned = start.is_aligned(PAGE_SIZE) && size % PAGE_SIZE == 0 && guard % PAGE_SIZE == 0;
+    if !aligned || guard >= size {
+        return Err(VmError::Unaligned);
+    }
+    let mut regions = REGIONS.lock();
+    let start = start.as_u64();
+    if regions
+        .iter()
+        .flatten()
+        .any(|r| r.overlaps(start, start + size))
+    {
+        return Err(VmError::Overlapping);
+    }
+    insert(&mut regions, name, start, size, guard)
+}
+
+fn insert(
+    regions: &mut [Option<VirtRegion>; MAX_REGIONS],
+    name: &'static str,
+    start: u64,
+    size: u64,
+    guard: u64,
+) -> Result<VirtRegion, VmError> {
+    let slot = regions
+        .iter_mut()
+        .find(|r| r.is_none())
+        .ok_or(VmError::TooManyRegions)?;
+    let region = VirtRegion {
+        name,
+        start: VirtAddr::new(start),
+        size,
+        guard,
+        backing: Backing::Unmapped,
+    };
+    *slot = Some(region);
+    Ok(region)
+}
+
+fn set_backing(region: &VirtRegion, backing: Backing) -> Result<VirtRegion, VmError> {
+    let mut regions = REGIONS.lock();
+    let stored = regions
+        .iter_mut()
+        .flatten()
+        .find(|r| r.start == region.start)
+        .ok_or(VmError::NotReserved)?;
+    if backing != Backing::Unmapped && stored.backing != Backing::Unmapped {
+        return Err(VmError::AlreadyMapped);
+    }
+    stored.backing = backing;
+    Ok(*stored)
+}
+
+// Returns the region containing `addr`.
+pub fn region_containing(addr: VirtAddr) -> Option<VirtRegion> {
+    REGIONS
+        .try_lock()?
+        .iter()
+        .flatten()
+        .find(|r| r.contains(addr))
+        .copied()
+}
+
+// Calls `f` for every reserved region.
+pub fn for_each_region(mut f: impl FnMut(&VirtRegion)) {
+    for region in REGIONS.lock().iter().flatten() {
+        f(region);
+    }
+}
+
+// Maps the pages of a reserved region to freshly allocated frames.
+pub fn map(region: &VirtRegion, flags: PageTableFlags) -> Result<VirtRegion, VmError> {
+    let region = set_backing(region, Backing::Allocated)?;
+    let result = map_pages(&region, flags, |frame_allocator, _| {
+        frame_allocator.allocate_frame()
+    });
+    finish_mapping(region, result)
+}
+
+// Maps the pages of a reserved region to the frames starting at `phys`.
+pub fn map_to_phys(
+    region: &VirtRegion,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<VirtRegion, VmError> {
+    if !phys.is_aligned(PAGE_SIZE) {
+        return Err(VmError::Unaligned);
+    }
+    let region = set_backing(region, Backing::Physical(phys))?;
+    let first = PhysFrame::containing_address(phys);
+    let result = map_pages(&region, flags, |_, index| Some(first + index));
+    finish_mapping(region, result)
+}
+
+// Backs the pages of a reserved region on first touch. The guard still faults.
+pub fn map_lazy(region: &VirtRegion, flags: PageTableFlags) -> Result<VirtRegion, VmError> {
+    let region = set_backing(region, Backing::Lazy)?;
+    let start = region.start + region.guard;
+    match fault::register_lazy_region(region.name, start, region.size - region.guard, flags) {
+        Ok(_) => Ok(region),
+        Err(error) => {
+            set_backing(&region, Backing::Unmapped)?;
+            Err(match error {
+                fault::RegionError::Unaligned => VmError::Unaligned,
+                fault::RegionError::Overlapping => VmError::Overlapping,
+                fault::RegionError::TooManyRegions => VmError::TooManyRegions,
+                // vmm regions are all in the higher half, which is shared.
+                fault::RegionError::NotShared => VmError::OutOfAddressSpace,
+            })
+        }
+    }
+}
+
+fn finish_mapping(region: VirtRegion, result: Result<(), VmError>) -> Result<VirtRegion, VmError> {
+    match result {
+        Ok(()) => Ok(region),
+        Err(error) => {
+            set_backing(&region, Backing::Unmapped)?;
+            Err(error)
+        }
+    }
+}
+
+// Maps every page of `region` to the frame returned by `frame_for` for its index.
+// Undoes the mapping if a page can't be mapped.
+fn map_pages<F>(region: &VirtRegion, flags: PageTableFlags, mut frame_for: F) -> Result<(), VmError>
+where
+    F: FnMut(&mut super::bitmap::BitmapFrameAllocator, u64) -> Option<PhysFrame>,
+{
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let mapper = mapper.as_mut().ok_or(VmError::MapFailed)?;
+    let frame_allocator = frame_allocator.as_mut().ok_or(VmError::MapFailed)?;
+    let flags = flags | PageTableFlags::PRESENT | protect::no_execute();
+    for (index, page) in region.pages().enumerate() {
+        let error = match frame_for(frame_allocator, index as u64) {
+            Some(frame) => match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    continue;
+                }
+                Err(_) => {
+                    if region.backing == Backing::Allocated {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                    VmError::MapFailed
+                }
+            },
+            None => VmError::OutOfMemory,
+        };
+        let free_frames = region.backing == Backing::Allocated;
+        for page in region.pages().take(index) {
+            unmap_page(mapper, frame_allocator, page, free_frames);
+        }
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn unmap_page(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    page: Page<Size4KiB>,
+    free_frame: bool,
+) {
+    if let Ok((frame, flush)) = mapper.unmap(page) {
+        flush.flush();
+        if free_frame {
+            cow::release_frame(frame, frame_allocator);
+        }
+    }
+}
+
+// Unmaps the pages of a region and frees the frames it allocated.
+// The region stays reserved.
+pub fn unmap(region: &VirtRegion) -> Result<VirtRegion, VmError> {
+    let region = {
+        let regions = REGIONS.lock();
+        let stored = regions.iter().flatten().find(|r| r.start == region.start);
+        *stored.ok_or(VmError::NotReserved)?
+    };
+    match region.backing {
+        Backing::Unmapped => {}
+        Backing::Lazy => {
+            fault::unregister_region(region.start + region.guard);
+        }
+        Backing::Allocated | Backing::Physical(_) => {
+            let mut mapper = MAPPER.lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            let mapper = mapper.as_mut().ok_or(VmError::MapFailed)?;
+            let frame_allocator = frame_allocator.as_mut().ok_or(VmError::MapFailed)?;
+            let free_frames = region.backing == Backing::Allocated;
+            for page in region.pages() {
+                unmap_page(mapper, frame_allocator, page, free_frames);
+            }
+        }
+    }
+    set_backing(&region, Backing::Unmapped)
+}
+
+// Gives the address range of an unmapped region back.
+pub fn release(region: &VirtRegion) -> Result<(), VmError> {
+    let mut regions = REGIONS.lock();
+    let slot = regions
+        .iter_mut()
+        .find(|r| matches!(r, Some(r) if r.start == region.start))
+        .ok_or(VmError::NotReserved)?;
+    if slot.map_or(false, |r| r.backing != Backing::Unmapped) {
+        return Err(VmError::AlreadyMapped);
+    }
+    *slot = None;
+    Ok(())
+}
+
+// Reserves a region in the kernel half and maps it to fresh frames.
+pub fn allocate(
+    name: &'static str,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<VirtRegion, VmError> {
+    let region = reserve(name, size, PAGE_SIZE)?;
+    map(&region, flags).map_err(|error| {
+        let _ = release(&region);
+        error
+    })
+}
+
+// Maps `size` bytes of physical memory starting at `phys` into the kernel half.
+pub fn map_phys(
+    name: &'static str,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<VirtRegion, VmError> {
+    let region = reserve(name, size, PAGE_SIZE)?;
+    map_to_phys(&region, phys, flags).map_err(|error| {
+        let _ = release(&region);
+        error
+    })
+}
+
+// Maps the frames of an allocated region again in a new region. Both share
+// the frames copy-on-write, so the copy keeps the current contents.
+pub fn snapshot(name: &'static str, region: &VirtRegion) -> Result<VirtRegion, VmError> {
+    if region.backing != Backing::Allocated {
+        return Err(VmError::NotReserved);
+    }
+    let copy = reserve(name, region.size - region.guard, PAGE_SIZE)?;
+    let copy = set_backing(&copy, Backing::Allocated)?;
+    let result = {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or(VmError::MapFailed)?;
+        let src = region.pages().next().ok_or(VmError::MapFailed)?;
+        let dst = copy.pages().next().ok_or(VmError::MapFailed)?;
+        let count = (region.size - region.guard) / PAGE_SIZE;
+        cow::snapshot_range(mapper, src, dst, count)
+    };
+    result.map(|_| copy).map_err(|error| {
+        let _ = free(&copy);
+        match error {
+            cow::CowError::OutOfMemory => VmError::OutOfMemory,
+            _ => VmError::MapFailed,
+        }
+    })
+}
+
+// Unmaps and releases a region.
+pub fn free(region: &VirtRegion) -> Result<(), VmError> {
+    unmap(region)?;
+    release(region)
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
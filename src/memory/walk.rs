@@ -0,0 +1,137 @@
+use super::{effective_flags, MappedSize};
+use crate::println;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+// A single leaf entry of the page table hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: MappedSize,
+    pub flags: PageTableFlags,
+}
+
+// Adjacent mappings that are physically contiguous and share their flags.
+// `end` is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub phys: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+// Calls `f` for every leaf mapping of the active level 4 table in ascending
+// virtual address order. The complete physical memory must be mapped at
+// `physical_memory_offset`.
+pub unsafe fn walk(physical_memory_offset: VirtAddr, f: impl FnMut(Mapping)) {
+    let (level_4_table_frame, _) = Cr3::read();
+    let virt = physical_memory_offset + level_4_table_frame.start_address().as_u64();
+    let level_4_table: *const PageTable = virt.as_ptr();
+    walk_table(&*level_4_table, physical_memory_offset, f)
+}
+
+// Same as `walk`, for an arbitrary level 4 table.
+pub unsafe fn walk_table(
+    level_4_table: &PageTable,
+    physical_memory_offset: VirtAddr,
+    mut f: impl FnMut(Mapping),
+) {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    walk_level(level_4_table, 4, 0, flags, physical_memory_offset, &mut f);
+}
+
+unsafe fn walk_level(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    upper_flags: PageTableFlags,
+    physical_memory_offset: VirtAddr,
+    f: &mut impl FnMut(Mapping),
+) {
+    for (index, entry) in table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let virt = base | (index as u64) << (12 + 9 * (level as u64 - 1));
+        let flags = effective_flags(upper_flags, entry.flags());
+        let size = match level {
+            3 if entry.flags().contains(PageTableFlags::HUGE_PAGE) => MappedSize::Size1GiB,
+            2 if entry.flags().contains(PageTableFlags::HUGE_PAGE) => MappedSize::Size2MiB,
+            1 => MappedSize::Size4KiB,
+            _ => {
+                let next = physical_memory_offset + entry.addr().as_u64();
+                let next_table: *const PageTable = next.as_ptr();
+                walk_level(
+                    &*next_table,
+                    level - 1,
+                    virt,
+                    flags,
+                    physical_memory_offset,
+                    f,
+                );
+                continue;
+            }
+        };
+        f(Mapping {
+            virt: VirtAddr::new_truncate(virt),
+            // Drops the PAT bit of huge page entries.
+            phys: entry.addr().align_down(size.bytes()),
+            size,
+            flags,
+        });
+    }
+}
+
+// Calls `f` for every maximal range of mappings of the active level 4 table.
+pub unsafe fn for_each_range(physical_memory_offset: VirtAddr, mut f: impl FnMut(MappedRange)) {
+    let mut current: Option<MappedRange> = None;
+    walk(physical_memory_offset, |mapping| {
+        // Wraps instead of panicking for a mapping at the end of a canonical half.
+        let end = mapping.virt.as_u64().wrapping_add(mapping.size.bytes());
+        let end = VirtAddr::new_truncate(end);
+        if let Some(range) = current.as_mut() {
+            let phys_end = range.phys + range.size();
+            if range.end == mapping.virt && phys_end == mapping.phys && range.flags == mapping.flags
+            {
+                range.end = end;
+                return;
+            }
+            f(*range);
+        }
+        current = Some(MappedRange {
+            start: mapping.virt,
+            end,
+            phys: mapping.phys,
+            flags: mapping.flags,
+        });
+    });
+    if let Some(range) = current {
+        f(range);
+    }
+}
+
+// Prints all mapped ranges of the active level 4 table.
+pub unsafe fn dump(physical_memory_offset: VirtAddr) {
+    for_each_range(physical_memory_offset, |range| {
+        println!(
+            "{:#014x}-{:#014x} -> {:#x} {:?}",
+            range.start.as_u64(),
+            range.end.as_u64(),
+            range.phys.as_u64(),
+            range.flags
+        );
+    });
+}
@@ -0,0 +1,161 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+pub const MAX_CPUS: usize = 16;
+
+// Offsets into `PerCpu` for code that addresses it through gs directly.
+pub const SELF_OFFSET: usize = 0;
+pub const SCRATCH_STACK_OFFSET: usize = 8;
+
+// The data of one CPU. The GS base of each CPU points to its own entry, so it
+// can be reached without locks or knowing the CPU id.
+#[repr(C)]
+pub struct PerCpu {
+    // Address of this entry, so it can be loaded with a single gs-relative move.
+    self_ptr: AtomicU64,
+    // Somewhere to save the stack pointer while switching stacks, e.g. on
+    // system call entry.
+    scratch_stack: AtomicU64,
+    ticks: AtomicU64,
+    current_task: AtomicUsize,
+    cpu_id: AtomicU32,
+    apic_id: AtomicU32,
+    interrupt_depth: AtomicU32,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            self_ptr: AtomicU64::new(0),
+            scratch_stack: AtomicU64::new(0),
+            ticks: AtomicU64::new(0),
+            current_task: AtomicUsize::new(0),
+            cpu_id: AtomicU32::new(0),
+            apic_id: AtomicU32::new(0),
+            interrupt_depth: AtomicU32::new(0),
+        }
+    }
+
+    pub fn cpu_id(&self) -> u32 {
+        self.cpu_id.load(Ordering::Relaxed)
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    // Timer interrupts this CPU has handled.
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    // Id of the task running on this CPU, 0 if there is none.
+    pub fn current_task(&self) -> usize {
+        self.current_task.load(Ordering::Relaxed)
+    }
+
+    pub fn set_current_task(&self, id: usize) {
+        self.current_task.store(id, Ordering::Relaxed);
+    }
+
+    pub fn interrupt_depth(&self) -> u32 {
+        self.interrupt_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn scratch_stack(&self) -> u64 {
+        self.scratch_stack.load(Ordering::Relaxed)
+    }
+
+    pub fn set_scratch_stack(&self, rsp: u64) {
+        self.scratch_stack.store(rsp, Ordering::Relaxed);
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NEW_CPU: PerCpu = PerCpu::new();
+static CPUS: [PerCpu; MAX_CPUS] = [NEW_CPU; MAX_CPUS];
+static CPU_COUNT: AtomicU32 = AtomicU32::new(0);
+// Set once the boot CPU has its GS base, every other CPU sets it before it
+// runs anything else.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// Points the GS base of the calling CPU at a free entry. The boot CPU calls it
+// from `crate::init`, other CPUs have to call it first thing.
+pub fn init() -> &'static PerCpu {
+    let cpu_id = CPU_COUNT.fetch_add(1, Ordering::Relaxed);
+    assert!((cpu_id as usize) < MAX_CPUS, "too many CPUs");
+    let cpu = &CPUS[cpu_id as usize];
+    let addr = VirtAddr::from_ptr(cpu);
+    cpu.self_ptr.store(addr.as_u64(), Ordering::Relaxed);
+    cpu.cpu_id.store(cpu_id, Ordering::Relaxed);
+    // The initial APIC id, which is available before the local APIC is mapped.
+    cpu.apic_id
+        .store(unsafe { __cpuid(1) }.ebx >> 24, Ordering::Relaxed);
+    GsBase::write(addr);
+    // There is no user mode yet, so both halves of swapgs see the same data.
+    KernelGsBase::write(addr);
+    INITIALIZED.store(true, Ordering::Release);
+    cpu
+}
+
+// The data of the calling CPU. Must not be used before `init`.
+pub fn current() -> &'static PerCpu {
+    let ptr: *const PerCpu;
+    unsafe {
+        // Loads `self_ptr`, which is at SELF_OFFSET.
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        &*ptr
+    }
+}
+
+// The id of the calling CPU, 0 before `init`.
+pub fn cpu_id() -> u32 {
+    if INITIALIZED.load(Ordering::Acquire) {
+        current().cpu_id()
+    } else {
+        0
+    }
+}
+
+pub fn cpu_count() -> u32 {
+    CPU_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn cpu(id: u32) -> Option<&'static PerCpu> {
+    if id < cpu_count() {
+        Some(&CPUS[id as usize])
+    } else {
+        None
+    }
+}
+
+pub fn in_interrupt() -> bool {
+    INITIALIZED.load(Ordering::Acquire) && current().interrupt_depth() > 0
+}
+
+// Tracks the interrupt nesting depth of the calling CPU while it is alive.
+pub struct InterruptGuard {
+    cpu: &'static PerCpu,
+}
+
+impl InterruptGuard {
+    pub fn enter() -> Self {
+        let cpu = current();
+        cpu.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+        Self { cpu }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        self.cpu.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Called by the timer interrupt handler.
+pub(crate) fn tick() {
+    current().ticks.fetch_add(1, Ordering::Relaxed);
+}
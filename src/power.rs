@@ -0,0 +1,211 @@
+use crate::acpi::{fadt::Fadt, AddressSpace, SdtHeader, SDT_HEADER_SIZE};
+use crate::memory::phys_to_virt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+// PM1 control register bits.
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+
+// AML opcodes needed to find the \_S5 package.
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+
+static REBOOT_ON_PANIC: AtomicBool = AtomicBool::new(false);
+
+// Makes the panic handler reboot instead of halting.
+pub fn set_reboot_on_panic(enabled: bool) {
+    REBOOT_ON_PANIC.store(enabled, Ordering::Relaxed);
+}
+
+pub fn reboot_on_panic() -> bool {
+    REBOOT_ON_PANIC.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    NoFadt,
+    InvalidDsdt,
+    // The DSDT has no `_S5_` package this parser understands.
+    NoS5Package,
+    NoPm1Control,
+}
+
+// The SLP_TYPa and SLP_TYPb values of the S5 (soft off) sleep state.
+// Only understands the `Name (_S5, Package () { ... })` form every firmware
+// uses, so it doesn't need an AML interpreter.
+pub fn s5_sleep_types() -> Result<(u16, u16), PowerError> {
+    let fadt = Fadt::find().ok_or(PowerError::NoFadt)?;
+    let dsdt = SdtHeader::read(fadt.dsdt);
+    if !dsdt.is_valid() {
+        return Err(PowerError::InvalidDsdt);
+    }
+    let aml = unsafe {
+        let start = phys_to_virt(dsdt.address + SDT_HEADER_SIZE).as_ptr::<u8>();
+        core::slice::from_raw_parts(start, dsdt.length as usize - SDT_HEADER_SIZE as usize)
+    };
+    // `_S5_` can also show up as a reference before the definition.
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, window)| *window == b"_S5_")
+        .find_map(|(name, _)| s5_package(aml, name))
+        .ok_or(PowerError::NoS5Package)
+}
+
+// Parses the package of the `_S5_` at `name`, if it is a NameOp.
+fn s5_package(aml: &[u8], name: usize) -> Option<(u16, u16)> {
+    // A root prefix or any number of parent prefixes can come between the
+    // NameOp and the name.
+    let mut op = name.checked_sub(1)?;
+    if aml[op] == b'\\' {
+        op = op.checked_sub(1)?;
+    } else {
+        while aml[op] == b'^' {
+            op = op.checked_sub(1)?;
+        }
+    }
+    let rest = aml.get(name + 4..)?;
+    if aml[op] != NAME_OP || rest.first() != Some(&PACKAGE_OP) {
+        return None;
+    }
+    // The top two bits of the PkgLength lead byte are the number of bytes following it.
+    let length_bytes = usize::from(rest.get(1)? >> 6);
+    // Skip the PackageOp, the PkgLength and NumElements.
+    let mut elements = rest.get(2 + length_bytes + 1..)?.iter();
+    let mut integer = || match *elements.next()? {
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        BYTE_PREFIX => elements.next().map(|&value| u16::from(value)),
+        _ => None,
+    };
+    let slp_typ_a = integer()?;
+    let slp_typ_b = integer()?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+// Switches the chipset to ACPI mode if the firmware left it in legacy mode.
+fn enable_acpi(fadt: &Fadt) {
+    let mut pm1a_control: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if unsafe { pm1a_control.read() } & SCI_EN != 0 {
+        return;
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    let mut smi_command: Port<u8> = Port::new(fadt.smi_command_port as u16);
+    unsafe { smi_command.write(fadt.acpi_enable) };
+    for _ in 0..1_000_000 {
+        if unsafe { pm1a_control.read() } & SCI_EN != 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// Enters S5 through the FADT's PM1 control registers. Returns if that
+// isn't possible or didn't work.
+fn acpi_power_off() -> Result<(), PowerError> {
+    let fadt = Fadt::find().ok_or(PowerError::NoFadt)?;
+    let (slp_typ_a, slp_typ_b) = s5_sleep_types()?;
+    if fadt.pm1a_control_block == 0 {
+        return Err(PowerError::NoPm1Control);
+    }
+    enable_acpi(&fadt);
+    unsafe {
+        let mut pm1a_control: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+        pm1a_control.write(slp_typ_a << SLP_TYP_SHIFT | SLP_EN);
+        if fadt.pm1b_control_block != 0 {
+            let mut pm1b_control: Port<u16> = Port::new(fadt.pm1b_control_block as u16);
+            pm1b_control.write(slp_typ_b << SLP_TYP_SHIFT | SLP_EN);
+        }
+    }
+    // Powering off takes a moment.
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+    match acpi_power_off() {
+        Ok(()) => crate::println!("Shutdown failed, it is now safe to turn off the computer."),
+        Err(error) => crate::println!(
+            "Shutdown failed ({:?}), it is now safe to turn off the computer.",
+            error
+        ),
+    }
+    crate::hlt_loop();
+}
+
+// Resets through the FADT's reset register.
+fn acpi_reset() {
+    let fadt = match Fadt::find() {
+        Some(fadt) => fadt,
+        None => return,
+    };
+    let register = match fadt.reset_register {
+        Some(register) => register,
+        None => return,
+    };
+    match register.address_space {
+        AddressSpace::SystemIo => unsafe {
+            Port::<u8>::new(register.address as u16).write(fadt.reset_value)
+        },
+        AddressSpace::SystemMemory => unsafe {
+            let ptr = phys_to_virt(PhysAddr::new(register.address)).as_mut_ptr::<u8>();
+            ptr.write_volatile(fadt.reset_value);
+        },
+        // Only the PCI host bridge at bus 0 is allowed, which also resets
+        // through port 0xcf9.
+        AddressSpace::PciConfig | AddressSpace::Other(_) => return,
+    }
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+// Pulses the CPU reset line through the keyboard controller.
+fn keyboard_controller_reset() {
+    let mut status: Port<u8> = Port::new(0x64);
+    unsafe {
+        // Wait until the input buffer is empty.
+        for _ in 0..1_000_000 {
+            if status.read() & 0b10 == 0 {
+                break;
+            }
+        }
+        status.write(0xfe);
+    }
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+// Loads an empty IDT and raises an exception, which can't be delivered.
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    crate::hlt_loop();
+}
+
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    acpi_reset();
+    keyboard_controller_reset();
+    triple_fault();
+}
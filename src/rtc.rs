@@ -0,0 +1,307 @@
+use crate::acpi::fadt::Fadt;
+use crate::timer;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+// CMOS registers of the RTC.
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+const ALARM_INTERRUPT: u8 = 1 << 5;
+const BINARY_MODE: u8 = 1 << 2;
+const HOURS_24: u8 = 1 << 1;
+const PM: u8 = 1 << 7;
+
+// The index port is shared by every CMOS access.
+static CMOS: Mutex<(Port<u8>, Port<u8>)> = Mutex::new((Port::new(0x70), Port::new(0x71)));
+
+fn read_register(register: u8) -> u8 {
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        unsafe {
+            cmos.0.write(register);
+            cmos.1.read()
+        }
+    })
+}
+
+fn write_register(register: u8, value: u8) {
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        unsafe {
+            cmos.0.write(register);
+            cmos.1.write(value);
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn is_leap_year(year: u64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    // Seconds since 1970-01-01 00:00:00.
+    pub fn to_unix(&self) -> u64 {
+        let year = u64::from(self.year);
+        let days = (1970..year)
+            .map(|year| if is_leap_year(year) { 366 } else { 365 })
+            .sum::<u64>()
+            + (1..u64::from(self.month))
+                .map(|month| days_in_month(year, month))
+                .sum::<u64>()
+            + u64::from(self.day)
+            - 1;
+        days * 86400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+
+    pub fn from_unix(seconds: u64) -> DateTime {
+        let mut days = seconds / 86400;
+        let mut year = 1970;
+        loop {
+            let year_days = if is_leap_year(year) { 366 } else { 365 };
+            if days < year_days {
+                break;
+            }
+            days -= year_days;
+            year += 1;
+        }
+        let mut month = 1;
+        while days >= days_in_month(year, month) {
+            days -= days_in_month(year, month);
+            month += 1;
+        }
+        let time = seconds % 86400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: days as u8 + 1,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+// FADT's century register, zero if there is none.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+fn read_raw() -> [u8; 7] {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    let century = match CENTURY_REGISTER.load(Ordering::Relaxed) {
+        0 => 0,
+        register => read_register(register),
+    };
+    [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR]
+        .iter()
+        .enumerate()
+        .fold([0, 0, 0, 0, 0, 0, century], |mut raw, (i, &register)| {
+            raw[i] = read_register(register);
+            raw
+        })
+}
+
+// Reads the date and time from the RTC.
+pub fn read() -> DateTime {
+    // An update can still start during the reads, so read until two agree.
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let [second, minute, hour, day, month, year, century] = raw;
+    let status_b = read_register(STATUS_B);
+    let binary = |value: u8| {
+        if status_b & BINARY_MODE != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+    let mut hour_24 = binary(hour & !PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 AM is midnight and 12 PM noon.
+        hour_24 %= 12;
+        if hour & PM != 0 {
+            hour_24 += 12;
+        }
+    }
+    let century = match century {
+        0 => 20,
+        century => u16::from(binary(century)),
+    };
+    DateTime {
+        year: century * 100 + u16::from(binary(year)),
+        month: binary(month),
+        day: binary(day),
+        hour: hour_24,
+        minute: binary(minute),
+        second: binary(second),
+    }
+}
+
+// Unix time in nanoseconds at timestamp zero of `timer::timestamp`.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+// Reads the RTC once, the wall clock follows the monotonic clock from then on.
+pub fn init() -> DateTime {
+    if let Some(fadt) = Fadt::find() {
+        CENTURY_REGISTER.store(fadt.century, Ordering::Relaxed);
+    }
+    let now = read();
+    let boot_time = now.to_unix() * 1_000_000_000 - timer::timestamp().as_nanos() as u64;
+    BOOT_TIME.store(boot_time, Ordering::Relaxed);
+    now
+}
+
+// Nanoseconds since the Unix epoch.
+pub fn unix_nanos() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + timer::timestamp().as_nanos() as u64
+}
+
+// The current wall clock time, zero if `init` hasn't run.
+pub fn now() -> DateTime {
+    DateTime::from_unix(unix_nanos() / 1_000_000_000)
+}
+
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+// The `fn()` to call on the alarm, zero for none.
+static ALARM_CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+// Fires the RTC interrupt 32768 >> (rate - 1) times per second, rate 3 to 15.
+pub fn enable_periodic(rate: u8) {
+    let rate = rate.clamp(3, 15);
+    without_interrupts(|| {
+        let status_a = read_register(STATUS_A);
+        write_register(STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b | PERIODIC_INTERRUPT);
+    });
+    // Clear anything pending, or the interrupt never fires.
+    read_register(STATUS_C);
+}
+
+pub fn disable_periodic() {
+    let status_b = read_register(STATUS_B);
+    write_register(STATUS_B, status_b & !PERIODIC_INTERRUPT);
+}
+
+// Number of periodic interrupts so far.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+// Raises the RTC interrupt at the given time of day and calls `callback` from
+// the interrupt handler.
+pub fn set_alarm(hour: u8, minute: u8, second: u8, callback: Option<fn()>) {
+    ALARM_CALLBACK.store(callback.map_or(0, |f| f as usize), Ordering::Relaxed);
+    ALARM_FIRED.store(false, Ordering::Relaxed);
+    let status_b = read_register(STATUS_B);
+    let encode = |value: u8| {
+        if status_b & BINARY_MODE != 0 {
+            value
+        } else {
+            to_bcd(value)
+        }
+    };
+    let hour = if status_b & HOURS_24 != 0 {
+        encode(hour)
+    } else {
+        let hour_12 = match hour % 12 {
+            0 => 12,
+            hour_12 => hour_12,
+        };
+        encode(hour_12) | if hour >= 12 { PM } else { 0 }
+    };
+    write_register(SECONDS_ALARM, encode(second));
+    write_register(MINUTES_ALARM, encode(minute));
+    write_register(HOURS_ALARM, hour);
+    write_register(STATUS_B, status_b | ALARM_INTERRUPT);
+    read_register(STATUS_C);
+}
+
+pub fn clear_alarm() {
+    let status_b = read_register(STATUS_B);
+    write_register(STATUS_B, status_b & !ALARM_INTERRUPT);
+}
+
+pub fn alarm_fired() -> bool {
+    ALARM_FIRED.load(Ordering::Relaxed)
+}
+
+// Called by the RTC interrupt handler.
+pub fn handle_interrupt() {
+    // Reading status C acknowledges the interrupt.
+    let status_c = read_register(STATUS_C);
+    if status_c & PERIODIC_INTERRUPT != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    if status_c & ALARM_INTERRUPT != 0 {
+        ALARM_FIRED.store(true, Ordering::Relaxed);
+        let callback = ALARM_CALLBACK.load(Ordering::Relaxed);
+        if callback != 0 {
+            let callback: fn() = unsafe { core::mem::transmute(callback) };
+            callback();
+        }
+    }
+}
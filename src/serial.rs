@@ -1,25 +1,32 @@
+use crate::sync::IrqSpinlock;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqSpinlock<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3f8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSpinlock::new("SERIAL1", serial_port)
     };
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    })
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+// Prints through SERIAL1, or straight to the port if SERIAL1 is held, e.g. by
+// the code that panicked. For panic handlers, which must not wait for locks.
+pub fn force_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = match SERIAL1.try_lock() {
+        Some(mut serial_port) => serial_port.write_fmt(args),
+        None => unsafe { SerialPort::new(0x3f8) }.write_fmt(args),
+    };
 }
 
 #[macro_export]
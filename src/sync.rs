@@ -0,0 +1,127 @@
+use core::ops::{Deref, DerefMut};
+use x86_64::instructions::interrupts;
+
+#[cfg(feature = "lock_debug")]
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// No owner, any other value is the owning CPU's id plus one.
+#[cfg(feature = "lock_debug")]
+const NO_OWNER: u32 = 0;
+
+// A spinlock that keeps interrupts disabled while it is held, so an interrupt
+// handler can't spin on a lock that the code it interrupted is holding.
+// With the `lock_debug` feature it panics instead of deadlocking when a CPU
+// tries to take a lock it already holds.
+pub struct IrqSpinlock<T> {
+    name: &'static str,
+    inner: spin::Mutex<T>,
+    #[cfg(feature = "lock_debug")]
+    owner: AtomicU32,
+}
+
+pub struct IrqSpinlockGuard<'a, T> {
+    guard: Option<spin::MutexGuard<'a, T>>,
+    #[cfg(feature = "lock_debug")]
+    owner: &'a AtomicU32,
+    // Whether interrupts were enabled before the lock was taken.
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            inner: spin::Mutex::new(value),
+            #[cfg(feature = "lock_debug")]
+            owner: AtomicU32::new(NO_OWNER),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn lock(&self) -> IrqSpinlockGuard<T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        self.check_recursion();
+        let guard = self.inner.lock();
+        self.guard(guard, interrupts_enabled)
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(self.guard(guard, interrupts_enabled)),
+            None => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    fn guard<'a>(
+        &'a self,
+        guard: spin::MutexGuard<'a, T>,
+        interrupts_enabled: bool,
+    ) -> IrqSpinlockGuard<'a, T> {
+        #[cfg(feature = "lock_debug")]
+        self.owner.store(current_owner(), Ordering::Relaxed);
+        IrqSpinlockGuard {
+            guard: Some(guard),
+            #[cfg(feature = "lock_debug")]
+            owner: &self.owner,
+            interrupts_enabled,
+        }
+    }
+
+    // Panic handlers only use `try_lock`, see `serial::force_print`, so they
+    // can't end up here again for the lock that is held.
+    #[cfg(feature = "lock_debug")]
+    fn check_recursion(&self) {
+        if self.owner.load(Ordering::Relaxed) == current_owner() {
+            panic!("recursive locking of {}", self.name);
+        }
+    }
+
+    #[cfg(not(feature = "lock_debug"))]
+    fn check_recursion(&self) {}
+}
+
+#[cfg(feature = "lock_debug")]
+fn current_owner() -> u32 {
+    crate::percpu::cpu_id() + 1
+}
+
+impl<T> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lock_debug")]
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        // Unlock before interrupts can come in again.
+        self.guard = None;
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
@@ -1,20 +1,320 @@
-use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::percpu;
+use core::future::Future;
+use core::ops::{Add, Sub};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
 
-lazy_static! {
-    pub static ref TIMER: Mutex<Timer> = Mutex::new(Timer { value: 0 });
+pub mod hpet;
+pub mod tsc;
+pub mod wheel;
+
+// Input clock of the PIT in Hz.
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+// Timer interrupts per second unless `init` is given something else.
+pub const DEFAULT_FREQUENCY: u32 = 100;
+
+static FREQUENCY: AtomicU32 = AtomicU32::new(DEFAULT_FREQUENCY);
+static TICK_NANOS: AtomicU64 = AtomicU64::new(1_000_000_000 / DEFAULT_FREQUENCY as u64);
+static NANOS: AtomicU64 = AtomicU64::new(0);
+// Timer interrupts on all CPUs, percpu has the count of each.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    ZeroFrequency,
+}
+
+// Programs PIT channel 0 to interrupt about `frequency` times per second,
+// `frequency` returns what the divisor actually gives.
+// The APIC timer uses the same frequency if it takes over.
+pub fn init(frequency: u32) -> Result<(), TimerError> {
+    if frequency == 0 {
+        return Err(TimerError::ZeroFrequency);
+    }
+    let divisor = (PIT_FREQUENCY / frequency).clamp(1, 0xffff);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+    unsafe {
+        // Channel 0, low and high byte, mode 2 (rate generator).
+        command.write(0b0011_0100);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+    // The actual period, which differs from 1 / `frequency` by rounding.
+    let tick_nanos = u64::from(divisor) * 1_000_000_000 / u64::from(PIT_FREQUENCY);
+    FREQUENCY.store(PIT_FREQUENCY / divisor, Ordering::Relaxed);
+    TICK_NANOS.store(tick_nanos, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+// Stops PIT channel 0 once something else raises the timer interrupt.
+fn stop_pit() {
+    let mut command: Port<u8> = Port::new(0x43);
+    // Mode 0 doesn't count until it gets a count, which it never does.
+    unsafe { command.write(0b0011_0000) };
+}
+
+// Used by a timer source other than the PIT, which can hit the frequency exactly.
+pub fn set_tick_period(nanos: u64) {
+    TICK_NANOS.store(nanos, Ordering::Relaxed);
+}
+
+pub fn tick_period() -> Duration {
+    Duration::from_nanos(TICK_NANOS.load(Ordering::Relaxed))
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Called by the timer interrupt handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    percpu::tick();
+    // Only the boot CPU keeps time, the timers of other CPUs just count.
+    if percpu::cpu_id() == 0 {
+        NANOS.fetch_add(TICK_NANOS.load(Ordering::Relaxed), Ordering::Relaxed);
+        wheel::advance();
+    }
 }
 
+static USE_HPET: AtomicBool = AtomicBool::new(false);
+static HPET_TICKS: AtomicBool = AtomicBool::new(false);
+// Uptime when the HPET counter started, which the clock continues from.
+static HPET_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Makes the clock and `delay` use the HPET's main counter instead of counting
+// timer interrupts and waiting on the PIT, and recalibrates the TSC against it.
+// The HPET only raises the timer interrupt once `start_hpet_ticks` is called.
+pub fn init_hpet() -> Result<(), hpet::HpetError> {
+    let hpet = hpet::init()?;
+    if tsc::frequency().is_some() {
+        // 10ms.
+        tsc::set_frequency(tsc::calibrate(|| hpet.nanos(), 10_000_000));
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let offset = NANOS.load(Ordering::Relaxed).saturating_sub(hpet.nanos());
+        HPET_OFFSET.store(offset, Ordering::Relaxed);
+        USE_HPET.store(true, Ordering::Release);
+    });
+    Ok(())
+}
+
+// Makes HPET comparator 0 interrupt `frequency` times per second through I/O
+// APIC input `gsi` and stops the PIT. The caller routes `gsi` to the timer
+// vector. Legacy replacement isn't used, since it also takes IRQ 8 from the RTC.
+pub fn start_hpet_ticks(gsi: u8) -> Result<(), hpet::HpetError> {
+    let hpet = hpet::hpet().ok_or(hpet::HpetError::NotFound)?;
+    hpet.route(0, gsi)?;
+    hpet.start_periodic(0, Duration::from_secs(1) / frequency())?;
+    stop_pit();
+    set_tick_period(1_000_000_000 / u64::from(frequency()));
+    HPET_TICKS.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+// Whether the HPET raises the timer interrupt.
+pub fn hpet_ticks() -> bool {
+    HPET_TICKS.load(Ordering::Relaxed)
+}
+
+fn clock_nanos() -> u64 {
+    match hpet::hpet() {
+        Some(hpet) if USE_HPET.load(Ordering::Acquire) => {
+            HPET_OFFSET.load(Ordering::Relaxed) + hpet.nanos()
+        }
+        _ => NANOS.load(Ordering::Relaxed),
+    }
+}
+
+// Time since the timer was started, at tick resolution unless the HPET is used.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(clock_nanos())
+}
+
+// A high resolution timestamp from the TSC, or the uptime if there is none.
+// Doesn't take any locks, so it works in interrupt handlers.
+pub fn timestamp() -> Duration {
+    tsc::nanos().map_or_else(uptime, Duration::from_nanos)
+}
+
+// A point in time of the monotonic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(clock_nanos())
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration.as_nanos() as u64)
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+// Busy-waits `count` PIT input clock cycles on channel 2, which doesn't
+// interrupt, so it works with interrupts disabled and before the clock runs.
+pub fn pit_wait(count: u16) {
+    let mut gate: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    unsafe {
+        // Gate channel 2 off and the speaker off.
+        let value = gate.read() & !0b11;
+        gate.write(value);
+        // Channel 2, low and high byte, mode 0 (interrupt on terminal count).
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+        // Counting starts with the gate going high.
+        gate.write(value | 0b1);
+        // Bit 5 is the output of channel 2, set when the count reaches zero.
+        while gate.read() & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// Busy-waits at least `duration`, for drivers that need short delays.
+pub fn delay(duration: Duration) {
+    if let Some(hpet) = hpet::hpet() {
+        let end = hpet.nanos() + duration.as_nanos() as u64;
+        while hpet.nanos() < end {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+    let mut cycles = duration.as_nanos() as u64 * u64::from(PIT_FREQUENCY) / 1_000_000_000 + 1;
+    while cycles > 0 {
+        let count = cycles.min(0xffff);
+        pit_wait(count as u16);
+        cycles -= count;
+    }
+}
+
+// A deadline for polling loops and retries.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Timer {
-    value: u64,
+pub struct Timeout {
+    deadline: Instant,
 }
 
-impl Timer {
-    pub fn inc(&mut self) {
-        self.value += 1;
+impl Timeout {
+    pub fn new(duration: Duration) -> Timeout {
+        Timeout {
+            deadline: Instant::now() + duration,
+        }
     }
-    pub fn get(&self) -> u64 {
-        self.value
+
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.deadline.duration_since(Instant::now())
+    }
+
+    // Calls `poll` until it returns something or the timeout expires.
+    pub fn poll_until<T>(&self, mut poll: impl FnMut() -> Option<T>) -> Option<T> {
+        loop {
+            if let Some(value) = poll() {
+                return Some(value);
+            }
+            if self.expired() {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// Halts until `duration` passed. Spins instead if interrupts are disabled,
+// since nothing would wake the CPU.
+pub fn sleep(duration: Duration) {
+    let timeout = Timeout::new(duration);
+    while !timeout.expired() {
+        if x86_64::instructions::interrupts::are_enabled() {
+            x86_64::instructions::hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// A future that completes after a duration.
+pub struct Sleep {
+    deadline: Instant,
+    timer: Option<wheel::TimerId>,
+}
+
+pub fn sleep_async(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: Instant::now() + duration,
+        timer: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Poll::Ready(());
+        }
+        match self.timer {
+            Some(timer) if wheel::is_pending(timer) => wheel::update_waker(timer, cx.waker()),
+            _ => {
+                if let Some(timer) = self.timer.take() {
+                    wheel::cancel(timer);
+                }
+                // The wheel ticks coarser than the clock, so it might fire early.
+                let remaining = self.deadline.duration_since(now);
+                match wheel::schedule_wake(remaining, cx.waker().clone()) {
+                    Ok(timer) => self.timer = Some(timer),
+                    // Keep getting polled until a timer is free.
+                    Err(_) => cx.waker().wake_by_ref(),
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer {
+            wheel::cancel(timer);
+        }
     }
 }
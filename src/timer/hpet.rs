@@ -0,0 +1,202 @@
+use crate::acpi;
+use crate::memory::mmio::{CachePolicy, MmioRegion};
+use crate::memory::vmm::VmError;
+use core::time::Duration;
+use spin::Once;
+use x86_64::PhysAddr;
+
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const INTERRUPT_STATUS: usize = 0x020;
+const MAIN_COUNTER: usize = 0x0f0;
+
+// Set if the main counter is 64 bits wide.
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+
+const ENABLE: u64 = 1 << 0;
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+// Comparator configuration bits.
+const LEVEL_TRIGGERED: u64 = 1 << 1;
+const INTERRUPT_ENABLE: u64 = 1 << 2;
+const PERIODIC: u64 = 1 << 3;
+const PERIODIC_CAPABLE: u64 = 1 << 4;
+const VALUE_SET: u64 = 1 << 6;
+const ROUTE_SHIFT: u64 = 9;
+const ROUTE_MASK: u64 = 0x1f << ROUTE_SHIFT;
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+fn comparator_config(comparator: u8) -> usize {
+    0x100 + 0x20 * usize::from(comparator)
+}
+
+fn comparator_value(comparator: u8) -> usize {
+    0x108 + 0x20 * usize::from(comparator)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NotFound,
+    Map(VmError),
+    NoSuchComparator,
+    NotPeriodicCapable,
+    // The comparator can't be connected to that I/O APIC input.
+    RouteUnavailable,
+    // A 32 bit main counter wraps within minutes, which the clock can't handle.
+    NarrowCounter,
+}
+
+pub struct Hpet {
+    registers: MmioRegion,
+    // Length of a counter tick in femtoseconds.
+    period: u64,
+    comparators: u8,
+}
+
+impl Hpet {
+    fn map(address: PhysAddr) -> Result<Hpet, HpetError> {
+        let registers = MmioRegion::map("hpet", address, 0x400, CachePolicy::Uncached)
+            .map_err(HpetError::Map)?;
+        let capabilities: u64 = registers.read(CAPABILITIES);
+        if capabilities & COUNT_SIZE_CAP == 0 {
+            return Err(HpetError::NarrowCounter);
+        }
+        let hpet = Hpet {
+            registers,
+            period: capabilities >> 32,
+            comparators: ((capabilities >> 8) & 0x1f) as u8 + 1,
+        };
+        // Start counting from zero, with the legacy routes off.
+        hpet.write(CONFIGURATION, 0);
+        hpet.write(MAIN_COUNTER, 0);
+        for comparator in 0..hpet.comparators {
+            let config = hpet.read(comparator_config(comparator));
+            hpet.write(
+                comparator_config(comparator),
+                config & !(INTERRUPT_ENABLE | PERIODIC),
+            );
+        }
+        hpet.write(CONFIGURATION, ENABLE);
+        Ok(hpet)
+    }
+
+    fn read(&self, register: usize) -> u64 {
+        self.registers.read(register)
+    }
+
+    fn write(&self, register: usize, value: u64) {
+        self.registers.write(register, value)
+    }
+
+    pub fn frequency(&self) -> u64 {
+        (1_000_000_000_000_000 / u128::from(self.period)) as u64
+    }
+
+    pub fn comparators(&self) -> u8 {
+        self.comparators
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    // Nanoseconds since the HPET was initialized.
+    pub fn nanos(&self) -> u64 {
+        (u128::from(self.counter()) * u128::from(self.period) / FEMTOS_PER_NANO) as u64
+    }
+
+    fn ticks(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * FEMTOS_PER_NANO / u128::from(self.period)).max(1) as u64
+    }
+
+    fn check(&self, comparator: u8) -> Result<u64, HpetError> {
+        if comparator >= self.comparators {
+            return Err(HpetError::NoSuchComparator);
+        }
+        Ok(self.read(comparator_config(comparator)))
+    }
+
+    // Connects comparator 0 to ISA IRQ 0 and comparator 1 to ISA IRQ 8, in
+    // place of the PIT and the RTC.
+    pub fn set_legacy_replacement(&self, enabled: bool) {
+        let config = self.read(CONFIGURATION);
+        let config = if enabled {
+            config | LEGACY_REPLACEMENT
+        } else {
+            config & !LEGACY_REPLACEMENT
+        };
+        self.write(CONFIGURATION, config);
+    }
+
+    // The I/O APIC inputs a comparator can be connected to, one bit each.
+    pub fn routes(&self, comparator: u8) -> Result<u32, HpetError> {
+        Ok((self.check(comparator)? >> 32) as u32)
+    }
+
+    // Connects a comparator to an I/O APIC input, unused in legacy replacement mode.
+    pub fn route(&self, comparator: u8, gsi: u8) -> Result<(), HpetError> {
+        let config = self.check(comparator)?;
+        if gsi >= 32 || config >> 32 & (1 << gsi) == 0 {
+            return Err(HpetError::RouteUnavailable);
+        }
+        let config = (config & !ROUTE_MASK) | u64::from(gsi) << ROUTE_SHIFT;
+        self.write(comparator_config(comparator), config);
+        Ok(())
+    }
+
+    // Interrupts once after `delay`.
+    pub fn start_one_shot(&self, comparator: u8, delay: Duration) -> Result<(), HpetError> {
+        let config = self.check(comparator)?;
+        let config = (config & !(PERIODIC | LEVEL_TRIGGERED)) | INTERRUPT_ENABLE;
+        self.write(comparator_config(comparator), config);
+        self.write(
+            comparator_value(comparator),
+            self.counter() + self.ticks(delay),
+        );
+        Ok(())
+    }
+
+    // Interrupts every `period`.
+    pub fn start_periodic(&self, comparator: u8, period: Duration) -> Result<(), HpetError> {
+        let config = self.check(comparator)?;
+        if config & PERIODIC_CAPABLE == 0 {
+            return Err(HpetError::NotPeriodicCapable);
+        }
+        let ticks = self.ticks(period);
+        let config = (config & !LEVEL_TRIGGERED) | INTERRUPT_ENABLE | PERIODIC | VALUE_SET;
+        self.write(comparator_config(comparator), config);
+        // With VALUE_SET the first write sets the comparator, the second the period.
+        self.write(comparator_value(comparator), self.counter() + ticks);
+        self.write(comparator_value(comparator), ticks);
+        Ok(())
+    }
+
+    pub fn stop(&self, comparator: u8) -> Result<(), HpetError> {
+        let config = self.check(comparator)?;
+        self.write(
+            comparator_config(comparator),
+            config & !(INTERRUPT_ENABLE | PERIODIC),
+        );
+        // Clears a pending level triggered interrupt.
+        self.write(INTERRUPT_STATUS, 1 << comparator);
+        Ok(())
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+// The HPET, once `init` found it.
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.r#try()
+}
+
+// Maps the HPET the ACPI tables describe and starts its main counter.
+pub fn init() -> Result<&'static Hpet, HpetError> {
+    if let Some(hpet) = hpet() {
+        return Ok(hpet);
+    }
+    let table = acpi::hpet::Hpet::find().ok_or(HpetError::NotFound)?;
+    let hpet = Hpet::map(PhysAddr::new(table.address.address))?;
+    Ok(HPET.call_once(|| hpet))
+}
@@ -0,0 +1,132 @@
+use super::{pit_wait, PIT_FREQUENCY};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
+
+// Calibrate over this many PIT cycles, about 50ms.
+const CALIBRATION_CYCLES: u16 = 59_659;
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+// Nanoseconds per TSC cycle as a 32.32 fixed point number.
+static NANOS_PER_CYCLE: AtomicU64 = AtomicU64::new(0);
+// The TSC value and the nanoseconds at the last change of frequency.
+static BASE: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+// Odd while the frequency and base are being changed.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1) }.edx & (1 << 4) != 0
+}
+
+// An invariant TSC runs at a constant rate in all power states, so it can be
+// used as a clock.
+pub fn is_invariant() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+// The frequency the CPU reports in leaf 0x15, if it does.
+fn cpuid_frequency() -> Option<u64> {
+    if unsafe { __cpuid(0) }.eax < 0x15 {
+        return None;
+    }
+    let leaf = unsafe { __cpuid(0x15) };
+    if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+        return None;
+    }
+    Some(u64::from(leaf.ecx) * u64::from(leaf.ebx) / u64::from(leaf.eax))
+}
+
+fn measure_frequency() -> u64 {
+    let start = read();
+    pit_wait(CALIBRATION_CYCLES);
+    let cycles = read() - start;
+    cycles * u64::from(PIT_FREQUENCY) / u64::from(CALIBRATION_CYCLES)
+}
+
+// Determines the TSC frequency and starts the clock at zero.
+// Returns the frequency in Hz, or `None` if there is no TSC.
+pub fn init() -> Option<u64> {
+    if !is_supported() {
+        return None;
+    }
+    let frequency = cpuid_frequency().unwrap_or_else(measure_frequency);
+    BASE.store(read(), Ordering::Relaxed);
+    set_frequency(frequency);
+    INVARIANT.store(is_invariant(), Ordering::Relaxed);
+    Some(frequency)
+}
+
+// Replaces the frequency after a more precise calibration. The clock
+// continues from where it is instead of jumping to the new rate's idea of it.
+pub fn set_frequency(frequency: u64) {
+    let nanos_per_cycle = (1_000_000_000u128 << 32) / u128::from(frequency.max(1));
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let now = read();
+        BASE_NANOS.store(nanos_at(now), Ordering::Relaxed);
+        BASE.store(now, Ordering::Relaxed);
+        NANOS_PER_CYCLE.store(nanos_per_cycle as u64, Ordering::Relaxed);
+        FREQUENCY.store(frequency, Ordering::Relaxed);
+        SEQUENCE.fetch_add(1, Ordering::Release);
+    });
+}
+
+// Measures the frequency against `nanos`, a clock that is more precise than
+// the PIT, over `duration` nanoseconds.
+pub fn calibrate(nanos: impl Fn() -> u64, duration: u64) -> u64 {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let start = (read(), nanos());
+        while nanos() - start.1 < duration {
+            core::hint::spin_loop();
+        }
+        let end = (read(), nanos());
+        let cycles = u128::from(end.0 - start.0);
+        (cycles * 1_000_000_000 / u128::from(end.1 - start.1)) as u64
+    })
+}
+
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::Acquire) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+pub fn invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+fn nanos_at(tsc: u64) -> u64 {
+    let cycles = tsc.wrapping_sub(BASE.load(Ordering::Relaxed));
+    let nanos_per_cycle = NANOS_PER_CYCLE.load(Ordering::Relaxed);
+    BASE_NANOS.load(Ordering::Relaxed)
+        + ((u128::from(cycles) * u128::from(nanos_per_cycle)) >> 32) as u64
+}
+
+// Nanoseconds since `init`. Lock-free, so it can be used anywhere. `None` if
+// the TSC isn't invariant, since its rate could change underneath.
+pub fn nanos() -> Option<u64> {
+    if !invariant() {
+        return None;
+    }
+    frequency()?;
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+        if sequence % 2 == 1 {
+            core::hint::spin_loop();
+            continue;
+        }
+        let nanos = nanos_at(read());
+        fence(Ordering::Acquire);
+        if SEQUENCE.load(Ordering::Relaxed) == sequence {
+            return Some(nanos);
+        }
+    }
+}
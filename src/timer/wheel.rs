@@ -0,0 +1,234 @@
+use core::task::Waker;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const SLOTS: usize = 256;
+// Pending timers live in a fixed table, so scheduling never allocates and
+// expiring works in the interrupt handler.
+const MAX_TIMERS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId {
+    index: u16,
+    generation: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    TooManyTimers,
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    None,
+    Call(fn(usize), usize),
+    // Wakes the waker in `WAKERS` at the timer's index. The waker stays
+    // there until `cancel` or the next `schedule_wake` of that index drops it.
+    Wake,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    deadline: u64,
+    action: Action,
+    generation: u32,
+    pending: bool,
+    // Next entry in the same slot, or in the free list.
+    next: Option<u16>,
+}
+
+struct Wheel {
+    entries: [Entry; MAX_TIMERS],
+    slots: [Option<u16>; SLOTS],
+    free: Option<u16>,
+    // Ticks processed so far.
+    now: u64,
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+
+const NO_WAKER: Option<Waker> = None;
+static WAKERS: Mutex<[Option<Waker>; MAX_TIMERS]> = Mutex::new([NO_WAKER; MAX_TIMERS]);
+
+impl Wheel {
+    const fn new() -> Wheel {
+        let mut entries = [Entry {
+            deadline: 0,
+            action: Action::None,
+            generation: 0,
+            pending: false,
+            next: None,
+        }; MAX_TIMERS];
+        let mut i = 0;
+        while i < MAX_TIMERS - 1 {
+            entries[i].next = Some(i as u16 + 1);
+            i += 1;
+        }
+        Wheel {
+            entries,
+            slots: [None; SLOTS],
+            free: Some(0),
+            now: 0,
+        }
+    }
+
+    fn insert(&mut self, ticks: u64, action: Action) -> Result<TimerId, TimerError> {
+        let index = self.free.ok_or(TimerError::TooManyTimers)?;
+        let deadline = self.now + ticks.max(1);
+        let slot = deadline as usize % SLOTS;
+        let entry = &mut self.entries[usize::from(index)];
+        self.free = entry.next;
+        entry.deadline = deadline;
+        entry.action = action;
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.pending = true;
+        entry.next = self.slots[slot];
+        self.slots[slot] = Some(index);
+        Ok(TimerId {
+            index,
+            generation: entry.generation,
+        })
+    }
+
+    // Unlinks the entry at `index` from its slot and frees it.
+    fn remove(&mut self, index: u16) {
+        let slot = self.entries[usize::from(index)].deadline as usize % SLOTS;
+        let mut link = self.slots[slot];
+        let mut previous: Option<u16> = None;
+        while let Some(current) = link {
+            let next = self.entries[usize::from(current)].next;
+            if current == index {
+                match previous {
+                    Some(previous) => self.entries[usize::from(previous)].next = next,
+                    None => self.slots[slot] = next,
+                }
+                break;
+            }
+            previous = Some(current);
+            link = next;
+        }
+        let entry = &mut self.entries[usize::from(index)];
+        entry.pending = false;
+        entry.next = self.free;
+        self.free = Some(index);
+    }
+
+    fn is_pending(&self, id: TimerId) -> bool {
+        let entry = &self.entries[usize::from(id.index)];
+        entry.pending && entry.generation == id.generation
+    }
+
+    // Removes one timer of the current slot that is due.
+    fn pop_expired(&mut self) -> Option<(u16, Action)> {
+        let slot = self.now as usize % SLOTS;
+        let mut link = self.slots[slot];
+        while let Some(index) = link {
+            let entry = self.entries[usize::from(index)];
+            if entry.deadline <= self.now {
+                self.remove(index);
+                return Some((index, entry.action));
+            }
+            link = entry.next;
+        }
+        None
+    }
+}
+
+fn ticks(delay: Duration) -> u64 {
+    let tick = super::tick_period().as_nanos().max(1);
+    ((delay.as_nanos() + tick - 1) / tick) as u64
+}
+
+// Calls `callback(data)` from the timer interrupt handler after `delay`.
+// Callbacks must be short and must not take locks the interrupted code might hold.
+pub fn schedule(delay: Duration, callback: fn(usize), data: usize) -> Result<TimerId, TimerError> {
+    without_interrupts(|| {
+        WHEEL
+            .lock()
+            .insert(ticks(delay), Action::Call(callback, data))
+    })
+}
+
+// Wakes `waker` after `delay`.
+pub fn schedule_wake(delay: Duration, waker: Waker) -> Result<TimerId, TimerError> {
+    let (id, old) = without_interrupts(|| {
+        let id = WHEEL.lock().insert(ticks(delay), Action::Wake)?;
+        let old = WAKERS.lock()[usize::from(id.index)].replace(waker);
+        Ok((id, old))
+    })?;
+    // The waker of an earlier timer that fired, dropped with interrupts enabled.
+    drop(old);
+    Ok(id)
+}
+
+// Replaces the waker of a pending `schedule_wake` timer.
+pub fn update_waker(id: TimerId, waker: &Waker) {
+    without_interrupts(|| {
+        if WHEEL.lock().is_pending(id) {
+            let mut wakers = WAKERS.lock();
+            let slot = &mut wakers[usize::from(id.index)];
+            if !slot.as_ref().map_or(false, |w| w.will_wake(waker)) {
+                *slot = Some(waker.clone());
+            }
+        }
+    })
+}
+
+// Cancels a pending timer and drops its waker, which a fired timer keeps too.
+// Returns false if it already fired.
+pub fn cancel(id: TimerId) -> bool {
+    let (pending, waker) = without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        // The entry was reused, so the waker isn't ours either.
+        if wheel.entries[usize::from(id.index)].generation != id.generation {
+            return (false, None);
+        }
+        let pending = wheel.is_pending(id);
+        if pending {
+            wheel.remove(id.index);
+        }
+        (pending, WAKERS.lock()[usize::from(id.index)].take())
+    });
+    // Dropped with interrupts enabled, it might free memory.
+    drop(waker);
+    pending
+}
+
+pub fn is_pending(id: TimerId) -> bool {
+    without_interrupts(|| WHEEL.lock().is_pending(id))
+}
+
+// Advances the wheel by one tick and runs the timers that are due.
+// Called by the timer interrupt handler.
+pub fn advance() {
+    let mut wheel = match WHEEL.try_lock() {
+        Some(wheel) => wheel,
+        // Can't happen on one CPU, everyone else locks with interrupts off.
+        None => return,
+    };
+    wheel.now += 1;
+    loop {
+        let expired = wheel.pop_expired();
+        let (index, action) = match expired {
+            Some(expired) => expired,
+            None => break,
+        };
+        // Callbacks may schedule new timers.
+        drop(wheel);
+        match action {
+            Action::Call(callback, data) => callback(data),
+            Action::Wake => {
+                // Only woken by reference, dropping the waker might free
+                // memory while the interrupted code holds the heap lock.
+                if let Some(wakers) = WAKERS.try_lock() {
+                    if let Some(waker) = &wakers[usize::from(index)] {
+                        waker.wake_by_ref();
+                    }
+                }
+            }
+            Action::None => {}
+        }
+        wheel = WHEEL.lock();
+    }
+}
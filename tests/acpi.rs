@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::acpi::{self, fadt::Fadt, hpet::Hpet, madt::Madt, mcfg::Mcfg, AddressSpace};
+use osh1mc::memory;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn rsdp_found() {
+    let rsdp = acpi::rsdp().unwrap();
+    assert_eq!(rsdp.address.as_u64() % 16, 0);
+    assert!(acpi::tables().count() > 0);
+}
+
+#[test_case]
+fn tables_are_valid() {
+    for table in acpi::tables() {
+        assert!(table.is_valid());
+    }
+    assert!(acpi::find_table(b"FACP").is_some());
+    assert!(acpi::find_table(b"NONE").is_none());
+}
+
+#[test_case]
+fn madt() {
+    let madt = Madt::find().unwrap();
+    assert!(madt.cpus().any(|cpu| cpu.enabled));
+    assert!(madt.io_apic_for(0).is_some());
+    assert_ne!(madt.local_apic_address.as_u64(), 0);
+}
+
+#[test_case]
+fn fadt() {
+    let fadt = Fadt::find().unwrap();
+    assert_ne!(fadt.pm1a_control_block, 0);
+    assert_ne!(fadt.pm_timer_block, 0);
+    assert_ne!(fadt.dsdt.as_u64(), 0);
+    assert_eq!(acpi::SdtHeader::read(fadt.dsdt).signature(), "DSDT");
+}
+
+#[test_case]
+fn hpet() {
+    let hpet = Hpet::find().unwrap();
+    assert_eq!(hpet.address.address_space, AddressSpace::SystemMemory);
+    assert!(hpet.comparator_count >= 3);
+}
+
+#[test_case]
+fn mcfg_segments() {
+    // Only chipsets with PCI Express have one.
+    if let Some(mcfg) = Mcfg::find() {
+        for segment in mcfg.segments() {
+            assert!(segment.start_bus <= segment.end_bus);
+            assert!(segment.config_address(segment.start_bus, 0, 0).is_some());
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
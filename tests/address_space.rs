@@ -0,0 +1,151 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::memory::address_space::{AddressSpace, AddressSpaceError};
+use osh1mc::memory::protect::with_user_access;
+use osh1mc::memory::{self, bitmap::BitmapFrameAllocator, vmm};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use osh1mc::allocator;
+
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::install(mapper, frame_allocator);
+    test_main();
+    loop {}
+}
+
+// An address in a level 4 entry the kernel doesn't use.
+const USER_ADDR: u64 = 0x7000_0000_0000;
+
+fn free_frames() -> usize {
+    let frame_allocator = memory::FRAME_ALLOCATOR.lock();
+    frame_allocator.as_ref().unwrap().free_frames()
+}
+
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    unsafe { memory::translate_addr(addr, memory::physical_memory_offset()) }
+}
+
+#[test_case]
+fn separate_mappings() {
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let mut a = AddressSpace::new().unwrap();
+    let mut b = AddressSpace::new().unwrap();
+    a.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    b.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    with_user_access(|| unsafe {
+        a.switch();
+        ptr.write_volatile(1);
+        b.switch();
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(2);
+        a.switch();
+        assert_eq!(ptr.read_volatile(), 1);
+        AddressSpace::switch_to_kernel();
+    });
+    assert_eq!(translate(page.start_address()), None);
+}
+
+#[test_case]
+fn kernel_stays_mapped() {
+    let mut address_space = AddressSpace::new().unwrap();
+    let heap_value = alloc::boxed::Box::new(42);
+    unsafe { address_space.switch() };
+    assert!(address_space.is_active());
+    assert_eq!(*heap_value, 42);
+    unsafe { AddressSpace::switch_to_kernel() };
+    let kernel_page = Page::containing_address(VirtAddr::from_ptr(&*heap_value));
+    assert_eq!(
+        address_space.map_user(kernel_page, PageTableFlags::WRITABLE),
+        Err(AddressSpaceError::KernelAddress)
+    );
+}
+
+#[test_case]
+fn later_kernel_mappings_are_visible() {
+    let mut address_space = AddressSpace::new().unwrap();
+    // Made after the address space, so it must not need its own level 4 entry.
+    let region = vmm::allocate("late", 0x1000, PageTableFlags::WRITABLE).unwrap();
+    let ptr: *mut u64 = region.start.as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(7);
+        address_space.switch();
+        assert_eq!(ptr.read_volatile(), 7);
+        AddressSpace::switch_to_kernel();
+    }
+    vmm::free(&region).unwrap();
+}
+
+#[test_case]
+fn user_half_is_private() {
+    // The kernel's table has no entry here, so a new address space has none either.
+    let mut address_space = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    assert!(address_space
+        .map_user(page, PageTableFlags::WRITABLE)
+        .is_ok());
+    let kernel_page = Page::containing_address(VirtAddr::new(vmm::KERNEL_SPACE_START));
+    assert_eq!(
+        address_space.map_user(kernel_page, PageTableFlags::WRITABLE),
+        Err(AddressSpaceError::KernelAddress)
+    );
+}
+
+#[test_case]
+fn drop_frees_frames() {
+    let free = free_frames();
+    {
+        let mut address_space = AddressSpace::new().unwrap();
+        for i in 0..8 {
+            let page = Page::containing_address(VirtAddr::new(USER_ADDR + i * 0x20_0000));
+            address_space
+                .map_user(page, PageTableFlags::WRITABLE)
+                .unwrap();
+        }
+        assert!(free_frames() < free);
+    }
+    assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn clone_rejects_huge_pages() {
+    use x86_64::structures::paging::{Mapper, PhysFrame, Size2MiB};
+
+    let mut space = AddressSpace::new().unwrap();
+    let page = Page::<Size2MiB>::containing_address(VirtAddr::new(USER_ADDR));
+    // Never accessed, so any frame will do.
+    let frame = PhysFrame::containing_address(PhysAddr::new(0x20_0000));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+    unsafe {
+        space
+            .mapper()
+            .map_to(page, frame, flags, frame_allocator.as_mut().unwrap())
+            .unwrap()
+            .ignore();
+    }
+    drop(frame_allocator);
+    assert_eq!(space.clone_cow().err(), Some(AddressSpaceError::HugePage));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::acpi::madt::Madt;
+use osh1mc::interrupts::{self, apic};
+use osh1mc::memory::{self, bitmap::BitmapFrameAllocator};
+use osh1mc::timer;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use osh1mc::allocator;
+
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::install(mapper, frame_allocator);
+    interrupts::init_apic().expect("APIC initialization failed.");
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn madt_lists_apics() {
+    let madt = Madt::find().unwrap();
+    assert!(madt.io_apic_for(0).is_some());
+    // QEMU connects the PIT to pin 2 instead of 0.
+    assert_eq!(madt.isa_irq(0).gsi, 2);
+    assert_eq!(madt.isa_irq(1).gsi, 1);
+}
+
+#[test_case]
+fn local_apic_enabled() {
+    assert!(apic::local_apic().is_some());
+}
+
+#[test_case]
+fn apic_timer_ticks() {
+    let start = timer::ticks();
+    for _ in 0..3 {
+        x86_64::instructions::hlt();
+    }
+    assert!(timer::ticks() > start);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
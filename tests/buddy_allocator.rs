@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::memory::buddy::{BuddyFrameAllocator, MAX_ORDER};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+
+entry_point!(main);
+
+static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    test_main();
+    loop {}
+}
+
+fn free_blocks(frame_allocator: &BuddyFrameAllocator) -> [usize; MAX_ORDER + 1] {
+    let mut blocks = [0; MAX_ORDER + 1];
+    for (order, count) in blocks.iter_mut().enumerate() {
+        *count = frame_allocator.free_blocks(order);
+    }
+    blocks
+}
+
+#[test_case]
+fn small_frame_coalesces() {
+    let mut lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = lock.as_mut().unwrap();
+    let before = free_blocks(frame_allocator);
+    let free = frame_allocator.free_frames();
+    let frame: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().expect("out of frames");
+    assert_eq!(frame_allocator.free_frames(), free - 1);
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.free_frames(), free);
+    assert_eq!(free_blocks(frame_allocator), before);
+}
+
+#[test_case]
+fn large_frame_is_aligned() {
+    let mut lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = lock.as_mut().unwrap();
+    let free = frame_allocator.free_frames();
+    let frame: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().expect("no 2 MiB block");
+    assert_eq!(frame.start_address().as_u64() % Size2MiB::SIZE, 0);
+    assert_eq!(frame_allocator.free_frames(), free - 512);
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.free_frames(), free);
+}
+
+#[test_case]
+fn huge_frame_is_aligned() {
+    let mut lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = lock.as_mut().unwrap();
+    // QEMU's default machine has far less than 1 GiB of memory.
+    if let Some(frame) = FrameAllocator::<Size1GiB>::allocate_frame(frame_allocator) {
+        assert_eq!(frame.start_address().as_u64() % Size1GiB::SIZE, 0);
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+#[test_case]
+fn split_blocks_are_disjoint() {
+    let mut lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = lock.as_mut().unwrap();
+    let before = free_blocks(frame_allocator);
+    let a = frame_allocator.allocate_order(3).unwrap();
+    let b = frame_allocator.allocate_order(0).unwrap();
+    let c = frame_allocator.allocate_order(3).unwrap();
+    assert!(b < a || b >= a + 8);
+    assert!(c >= a + 8 || c + 8 <= a);
+    unsafe {
+        frame_allocator.deallocate_order(b, 0);
+        frame_allocator.deallocate_order(a, 3);
+        frame_allocator.deallocate_order(c, 3);
+    }
+    assert_eq!(free_blocks(frame_allocator), before);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
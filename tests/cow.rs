@@ -0,0 +1,165 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::memory::address_space::AddressSpace;
+use osh1mc::memory::cow;
+use osh1mc::memory::protect::with_user_access;
+use osh1mc::memory::vmm;
+use osh1mc::memory::{self, bitmap::BitmapFrameAllocator};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use osh1mc::allocator;
+
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::install(mapper, frame_allocator);
+    test_main();
+    loop {}
+}
+
+// An address in a level 4 entry the kernel doesn't use.
+const USER_ADDR: u64 = 0x7000_0000_0000;
+
+fn free_frames() -> usize {
+    let frame_allocator = memory::FRAME_ALLOCATOR.lock();
+    frame_allocator.as_ref().unwrap().free_frames()
+}
+
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    unsafe { memory::translate_addr(addr, memory::physical_memory_offset()) }
+}
+
+fn flags(addr: VirtAddr) -> PageTableFlags {
+    unsafe { memory::translate(addr, memory::physical_memory_offset()) }
+        .unwrap()
+        .flags
+}
+
+#[test_case]
+fn snapshot_shares_frames() {
+    let region = vmm::allocate("cow source", 0x2000, PageTableFlags::WRITABLE).unwrap();
+    let ptr: *mut u64 = region.start.as_mut_ptr();
+    unsafe { ptr.write_volatile(1) };
+    let copy = vmm::snapshot("cow copy", &region).unwrap();
+    let copy_ptr: *mut u64 = copy.start.as_mut_ptr();
+
+    assert_eq!(translate(region.start), translate(copy.start));
+    assert!(flags(region.start).contains(cow::COPY_ON_WRITE));
+    assert!(!flags(copy.start).contains(PageTableFlags::WRITABLE));
+    let frame = PhysFrame::containing_address(translate(region.start).unwrap());
+    assert_eq!(cow::mapping_count(frame), 2);
+    assert_eq!(unsafe { copy_ptr.read_volatile() }, 1);
+
+    vmm::free(&copy).unwrap();
+    vmm::free(&region).unwrap();
+}
+
+#[test_case]
+fn write_copies_page() {
+    let region = vmm::allocate("cow source", 0x1000, PageTableFlags::WRITABLE).unwrap();
+    let ptr: *mut u64 = region.start.as_mut_ptr();
+    unsafe { ptr.write_volatile(1) };
+    let copy = vmm::snapshot("cow copy", &region).unwrap();
+    let copy_ptr: *mut u64 = copy.start.as_mut_ptr();
+
+    unsafe { ptr.write_volatile(2) };
+    assert_ne!(translate(region.start), translate(copy.start));
+    assert!(flags(region.start).contains(PageTableFlags::WRITABLE));
+    assert_eq!(unsafe { copy_ptr.read_volatile() }, 1);
+
+    // The last mapping takes the frame over without copying.
+    let frame = translate(copy.start);
+    unsafe { copy_ptr.write_volatile(3) };
+    assert_eq!(translate(copy.start), frame);
+    assert!(!flags(copy.start).contains(cow::COPY_ON_WRITE));
+    assert_eq!(unsafe { ptr.read_volatile() }, 2);
+
+    vmm::free(&copy).unwrap();
+    vmm::free(&region).unwrap();
+}
+
+#[test_case]
+fn clone_address_space() {
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    with_user_access(|| unsafe {
+        parent.switch();
+        ptr.write_volatile(1);
+        AddressSpace::switch_to_kernel();
+    });
+    let mut child = parent.clone_cow().unwrap();
+    with_user_access(|| unsafe {
+        child.switch();
+        assert_eq!(ptr.read_volatile(), 1);
+        ptr.write_volatile(2);
+        parent.switch();
+        assert_eq!(ptr.read_volatile(), 1);
+        AddressSpace::switch_to_kernel();
+    });
+}
+
+#[test_case]
+fn clone_after_source_wrote() {
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    let mut child = parent.clone_cow().unwrap();
+    with_user_access(|| unsafe {
+        // The parent gets a copy, then the child takes the frame over.
+        parent.switch();
+        ptr.write_volatile(1);
+        child.switch();
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(2);
+        assert_eq!(ptr.read_volatile(), 2);
+        parent.switch();
+        assert_eq!(ptr.read_volatile(), 1);
+        AddressSpace::switch_to_kernel();
+    });
+}
+
+// Shares and frees frames once through every path the test below uses.
+fn share_and_free() {
+    let region = vmm::allocate("cow source", 0x4000, PageTableFlags::WRITABLE).unwrap();
+    let copy = vmm::snapshot("cow copy", &region).unwrap();
+    vmm::free(&region).unwrap();
+    let copy_ptr: *mut u64 = copy.start.as_mut_ptr();
+    unsafe { copy_ptr.write_volatile(1) };
+    vmm::free(&copy).unwrap();
+
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    let _child = parent.clone_cow().unwrap();
+}
+
+#[test_case]
+fn shared_frames_are_freed_once() {
+    // The refcount table and the kernel's page tables for the regions are
+    // allocated on first use and kept, so leave them out of the count.
+    share_and_free();
+    let free = free_frames();
+    share_and_free();
+    assert_eq!(free_frames(), free);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
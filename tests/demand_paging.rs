@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::memory::{self, bitmap::BitmapFrameAllocator, fault};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use osh1mc::allocator;
+
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::install(mapper, frame_allocator);
+    test_main();
+    loop {}
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    unsafe { memory::translate_addr(addr, memory::physical_memory_offset()) }.is_some()
+}
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .unwrap()
+        .free_frames()
+}
+
+#[test_case]
+fn touch_lazy_region() {
+    let start = VirtAddr::new(0xffff_e000_0000_0000);
+    let flags = PageTableFlags::WRITABLE;
+    fault::register_lazy_region("test", start, 16 * 4096, flags).unwrap();
+    let page_3 = start + 3 * 4096u64;
+    assert!(!is_mapped(page_3));
+
+    let ptr: *mut u64 = page_3.as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(is_mapped(page_3));
+    assert!(!is_mapped(start));
+
+    let free = free_frames();
+    fault::unregister_region(start).unwrap();
+    assert!(!is_mapped(page_3));
+    assert_eq!(free_frames(), free + 1);
+}
+
+#[test_case]
+fn overlapping_regions_are_rejected() {
+    let start = VirtAddr::new(0xffff_e000_1000_0000);
+    let flags = PageTableFlags::WRITABLE;
+    fault::register_lazy_region("first", start, 4 * 4096, flags).unwrap();
+    assert_eq!(
+        fault::register_lazy_region("second", start + 4096u64, 4096, flags),
+        Err(fault::RegionError::Overlapping)
+    );
+    fault::unregister_region(start).unwrap();
+}
+
+#[test_case]
+fn unresolvable_faults() {
+    let unregistered = VirtAddr::new(0xffff_e000_2000_0000);
+    let error_code = PageFaultErrorCode::CAUSED_BY_WRITE;
+    assert_eq!(
+        fault::handle_page_fault(unregistered, error_code),
+        Err(fault::FaultError::NotRegistered)
+    );
+
+    let start = VirtAddr::new(0xffff_e000_3000_0000);
+    let read_only = fault::register_lazy_region("ro", start, 4096, PageTableFlags::empty());
+    assert!(read_only.is_ok());
+    assert_eq!(
+        fault::handle_page_fault(start, error_code),
+        Err(fault::FaultError::ReadOnlyRegion("ro"))
+    );
+    fault::unregister_region(start).unwrap();
+}
+
+#[test_case]
+fn unshared_regions_are_rejected() {
+    // A level 4 entry of the lower half that the kernel doesn't use.
+    let start = VirtAddr::new(0x5555_0000_0000);
+    assert_eq!(
+        fault::register_lazy_region("user", start, 4096, PageTableFlags::WRITABLE),
+        Err(fault::RegionError::NotShared)
+    );
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use osh1mc::memory::protect::{self, Violation};
+use osh1mc::memory::{self, bitmap::BitmapFrameAllocator};
+use osh1mc::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    let violation = protect::classify(Cr2::read(), error_code);
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && violation == Violation::NoExecute
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("error code: {:?}, violation: {:?}", error_code, violation);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use osh1mc::allocator;
+
+    serial_print!("execute_heap::execute_heap...\t");
+    osh1mc::gdt::init();
+    TEST_IDT.load();
+
+    protect::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::install(mapper, frame_allocator);
+    if !protect::supported_features().no_execute {
+        serial_println!("[ok, no NX support]");
+        exit_qemu(QemuExitCode::Success);
+    }
+
+    // A single `ret`.
+    let code = Box::new([0xc3u8]);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+    panic!("Execution continued after executing the heap")
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info)
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::memory::bitmap::BitmapFrameAllocator;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+entry_point!(main);
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn allocate_and_free() {
+    let mut lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = lock.as_mut().unwrap();
+    let free = frame_allocator.free_frames();
+    let frame = frame_allocator.allocate_frame().expect("out of frames");
+    assert_eq!(frame_allocator.free_frames(), free - 1);
+    assert_eq!(
+        frame_allocator.used_frames() + frame_allocator.free_frames(),
+        frame_allocator.total_frames()
+    );
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.free_frames(), free);
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    let mut lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = lock.as_mut().unwrap();
+    let frame = frame_allocator.allocate_frame().unwrap();
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.allocate_frame(), Some(frame));
+    unsafe { frame_allocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn frames_are_unique() {
+    let mut lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = lock.as_mut().unwrap();
+    let mut frames = [None; 64];
+    for slot in frames.iter_mut() {
+        *slot = frame_allocator.allocate_frame();
+        assert!(slot.is_some());
+    }
+    for (i, a) in frames.iter().enumerate() {
+        for b in frames[i + 1..].iter() {
+            assert_ne!(a, b);
+        }
+    }
+    for frame in frames.iter() {
+        unsafe { frame_allocator.deallocate_frame(frame.unwrap()) };
+    }
+}
+
+#[test_case]
+fn contiguous_allocation() {
+    let mut lock = FRAME_ALLOCATOR.lock();
+    let frame_allocator = lock.as_mut().unwrap();
+    let free = frame_allocator.free_frames();
+    let range = frame_allocator
+        .allocate_contiguous(16, 16)
+        .expect("no contiguous frames");
+    assert_eq!(range.end - range.start, 16);
+    assert_eq!(range.start.start_address().as_u64() % (16 * 4096), 0);
+    assert_eq!(frame_allocator.free_frames(), free - 16);
+    unsafe { frame_allocator.deallocate_contiguous(range) };
+    assert_eq!(frame_allocator.free_frames(), free);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
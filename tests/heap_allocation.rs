@@ -8,20 +8,22 @@ extern crate alloc;
 
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use osh1mc::memory::BootInfoFrameAllocator;
+use osh1mc::memory::bitmap::BitmapFrameAllocator;
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use osh1mc::allocator;
-    use osh1mc::memory::{self, BootInfoFrameAllocator};
+    use osh1mc::memory;
     use x86_64::VirtAddr;
 
     osh1mc::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("msheap initialization failed.");
+    memory::install(mapper, frame_allocator);
     test_main();
     loop {}
 }
@@ -46,7 +48,7 @@ fn large_vec() {
     assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
 }
 
-use osh1mc::allocator::HEAP_SIZE;
+use osh1mc::allocator::{self, HEAP_SIZE};
 #[test_case]
 fn many_boxes() {
     for i in 0..HEAP_SIZE {
@@ -55,6 +57,113 @@ fn many_boxes() {
     }
 }
 
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn allocation_beyond_initial_heap() {
+    let n = 4 * HEAP_SIZE;
+    let mut vec = Vec::with_capacity(n);
+    for i in 0..n {
+        vec.push(i as u8);
+    }
+    assert_eq!(vec[n - 1], (n - 1) as u8);
+    assert!(allocator::heap_size() > HEAP_SIZE);
+}
+
+use alloc::vec;
+
+#[test_case]
+fn heap_shrinks() {
+    let n = 16 * HEAP_SIZE;
+    let vec = vec![1u8; n];
+    let grown = allocator::heap_size();
+    assert!(grown >= n);
+    drop(vec);
+    assert!(allocator::heap_size() < grown);
+    // The released memory can be used again.
+    let vec = vec![2u8; n];
+    assert_eq!(vec[n - 1], 2);
+}
+
+#[test_case]
+fn no_leaks() {
+    let before = allocator::heap_stats();
+    {
+        let boxed = Box::new([0u64; 16]);
+        let mut vec = Vec::new();
+        for i in 0..100 {
+            vec.push(i);
+        }
+        assert_eq!(boxed.len() + vec.len(), 116);
+    }
+    let after = allocator::heap_stats();
+    assert_eq!(after.live_allocations(), before.live_allocations());
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert!(after.allocations > before.allocations);
+    assert!(after.peak_bytes_in_use >= before.bytes_in_use + 16 * 8);
+}
+
+#[test_case]
+fn size_class_counts() {
+    use osh1mc::allocator::tracking::size_class_limit;
+
+    let before = allocator::heap_stats();
+    let small = Box::new(1u64);
+    let large = Box::new([0u8; 4096]);
+    let after = allocator::heap_stats();
+    let class_of = |size| (0..).find(|&i| size_class_limit(i).map_or(true, |l| l >= size));
+    let small_class = class_of(8).unwrap();
+    let large_class = class_of(4096).unwrap();
+    assert_eq!(size_class_limit(large_class), None);
+    assert_eq!(
+        after.size_classes[small_class].live,
+        before.size_classes[small_class].live + 1
+    );
+    assert_eq!(
+        after.size_classes[large_class].live,
+        before.size_classes[large_class].live + 1
+    );
+    drop(small);
+    drop(large);
+}
+
+#[cfg(feature = "heap_trace")]
+#[test_case]
+fn live_allocation_is_traced() {
+    use osh1mc::allocator::tracking::trace;
+
+    trace::clear();
+    let value = Box::new(7u32);
+    let addr = &*value as *const u32 as usize;
+    let mut found = false;
+    trace::for_each_live_allocation(|allocation| {
+        if allocation.ptr == addr {
+            found = true;
+            assert_eq!(allocation.size, 4);
+        }
+    });
+    assert!(found);
+    assert_eq!(trace::untracked(), 0);
+}
+
+#[cfg(feature = "fixed_size_block")]
+#[test_case]
+fn freed_block_is_reused() {
+    let first = Box::new([0u8; 24]);
+    let addr = &*first as *const _ as usize;
+    drop(first);
+    let second = Box::new([1u8; 24]);
+    assert_eq!(&*second as *const _ as usize, addr);
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     osh1mc::test_panic_handler(info);
@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use osh1mc::interrupts;
+use osh1mc::memory::{self, bitmap::BitmapFrameAllocator};
+use osh1mc::timer::{self, hpet, Instant};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use osh1mc::allocator;
+
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::install(mapper, frame_allocator);
+    timer::init_hpet().expect("HPET initialization failed.");
+    interrupts::init_apic().expect("APIC initialization failed.");
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn counter_runs() {
+    let hpet = hpet::hpet().unwrap();
+    // At least 10MHz by the specification.
+    assert!(hpet.frequency() >= 10_000_000);
+    let start = hpet.counter();
+    timer::pit_wait(1193);
+    assert!(hpet.counter() > start);
+}
+
+#[test_case]
+fn matches_pit() {
+    let hpet = hpet::hpet().unwrap();
+    let start = hpet.nanos();
+    // 50ms.
+    timer::pit_wait(59_659);
+    let elapsed = Duration::from_nanos(hpet.nanos() - start);
+    assert!(elapsed > Duration::from_millis(45), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(55), "{:?}", elapsed);
+}
+
+#[test_case]
+fn clock_uses_hpet() {
+    // Without the HPET the clock only advances on timer interrupts.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let start = Instant::now();
+        timer::pit_wait(1193);
+        assert!(Instant::now() > start);
+    });
+}
+
+#[test_case]
+fn tsc_recalibrated() {
+    let hpet = hpet::hpet().unwrap();
+    let start = (hpet.nanos(), timer::timestamp());
+    timer::delay(Duration::from_millis(50));
+    let elapsed = (
+        Duration::from_nanos(hpet.nanos() - start.0),
+        timer::timestamp() - start.1,
+    );
+    let tolerance = Duration::from_micros(500);
+    assert!(elapsed.1 + tolerance > elapsed.0, "{:?}", elapsed);
+    assert!(elapsed.1 < elapsed.0 + tolerance, "{:?}", elapsed);
+}
+
+#[test_case]
+fn drives_timer() {
+    assert!(timer::hpet_ticks());
+    let start = timer::ticks();
+    timer::sleep(Duration::from_millis(100));
+    let ticks = timer::ticks() - start;
+    let expected = u64::from(timer::frequency()) / 10;
+    assert!(ticks.abs_diff(expected) <= 1, "{} ticks", ticks);
+}
+
+#[test_case]
+fn comparators() {
+    let hpet = hpet::hpet().unwrap();
+    assert!(hpet.comparators() >= 3);
+    // Comparator 0 drives the timer.
+    hpet.start_one_shot(1, Duration::from_millis(1)).unwrap();
+    hpet.stop(1).unwrap();
+    hpet.start_periodic(1, Duration::from_millis(10)).unwrap();
+    hpet.stop(1).unwrap();
+    assert_eq!(
+        hpet.start_one_shot(hpet.comparators(), Duration::from_millis(1)),
+        Err(hpet::HpetError::NoSuchComparator)
+    );
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
@@ -0,0 +1,177 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use osh1mc::interrupts::irq::{self, IrqError, IrqReturn, MAX_SHARED};
+use osh1mc::interrupts::PICS;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    osh1mc::init();
+    // Nothing else allocates vectors, so this gets the first one.
+    assert_eq!(irq::allocate_vector(), Ok(VECTOR));
+    test_main();
+    loop {}
+}
+
+const VECTOR: u8 = 0x30;
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+static DATA: AtomicUsize = AtomicUsize::new(0);
+
+fn handled(data: usize) -> IrqReturn {
+    CALLS.fetch_add(1, Ordering::Relaxed);
+    DATA.store(data, Ordering::Relaxed);
+    IrqReturn::Handled
+}
+
+fn not_handled(_data: usize) -> IrqReturn {
+    CALLS.fetch_add(1, Ordering::Relaxed);
+    IrqReturn::NotHandled
+}
+
+fn raise() {
+    unsafe { asm!("int 0x30") };
+}
+
+#[test_case]
+fn builtin_handlers() {
+    assert_eq!(irq::stats(0x21).handlers, 1);
+    assert_eq!(irq::stats(0x2c).handlers, 1);
+    // The timer has a fixed handler but is counted too.
+    let start = irq::stats(0x20).count;
+    x86_64::instructions::hlt();
+    assert!(irq::stats(0x20).count > start);
+}
+
+#[test_case]
+fn dispatch_to_handler() {
+    CALLS.store(0, Ordering::Relaxed);
+    let start = irq::stats(VECTOR);
+    let id = irq::register_vector(VECTOR, "test", handled, 42).unwrap();
+    raise();
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(DATA.load(Ordering::Relaxed), 42);
+    let stats = irq::stats(VECTOR);
+    assert_eq!(stats.count, start.count + 1);
+    assert_eq!(stats.unhandled, start.unhandled);
+    assert_eq!(stats.handlers, 1);
+    irq::unregister(id).unwrap();
+    raise();
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(irq::stats(VECTOR).handlers, 0);
+    assert_eq!(irq::unregister(id), Err(IrqError::NotRegistered));
+}
+
+#[test_case]
+fn shared_vector() {
+    CALLS.store(0, Ordering::Relaxed);
+    let start = irq::stats(VECTOR);
+    let first = irq::register_vector(VECTOR, "first", not_handled, 0).unwrap();
+    let second = irq::register_vector(VECTOR, "second", not_handled, 0).unwrap();
+    raise();
+    assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+    assert_eq!(irq::stats(VECTOR).unhandled, start.unhandled + 1);
+    let third = irq::register_vector(VECTOR, "third", handled, 7).unwrap();
+    raise();
+    assert_eq!(CALLS.load(Ordering::Relaxed), 5);
+    assert_eq!(irq::stats(VECTOR).unhandled, start.unhandled + 1);
+    for id in [first, second, third].iter() {
+        irq::unregister(*id).unwrap();
+    }
+}
+
+#[test_case]
+fn too_many_handlers() {
+    let mut ids = [None; MAX_SHARED];
+    for id in ids.iter_mut() {
+        *id = Some(irq::register_vector(VECTOR, "test", handled, 0).unwrap());
+    }
+    assert_eq!(
+        irq::register_vector(VECTOR, "test", handled, 0),
+        Err(IrqError::TooManyHandlers)
+    );
+    for id in ids.iter().flatten() {
+        irq::unregister(*id).unwrap();
+    }
+}
+
+#[test_case]
+fn invalid_lines() {
+    assert_eq!(
+        irq::register_irq(0, "test", handled, 0),
+        Err(IrqError::InvalidIrq)
+    );
+    assert_eq!(
+        irq::register_irq(16, "test", handled, 0),
+        Err(IrqError::InvalidIrq)
+    );
+    assert_eq!(
+        irq::register_vector(0x21, "test", handled, 0),
+        Err(IrqError::InvalidVector)
+    );
+    // Not allocated.
+    assert_eq!(
+        irq::register_vector(0x3f, "test", handled, 0),
+        Err(IrqError::InvalidVector)
+    );
+}
+
+#[test_case]
+fn stale_id() {
+    let old = irq::register_vector(VECTOR, "old", handled, 0).unwrap();
+    irq::unregister(old).unwrap();
+    let new = irq::register_vector(VECTOR, "new", handled, 0).unwrap();
+    assert_eq!(new.vector(), old.vector());
+    assert_eq!(irq::unregister(old), Err(IrqError::NotRegistered));
+    assert_eq!(irq::stats(VECTOR).handlers, 1);
+    irq::unregister(new).unwrap();
+}
+
+#[test_case]
+fn allocate_vector() {
+    let first = irq::allocate_vector().unwrap();
+    let second = irq::allocate_vector().unwrap();
+    assert!(irq::DYNAMIC_VECTORS.contains(&first));
+    assert_ne!(first, second);
+    irq::free_vector(first);
+    assert_eq!(irq::allocate_vector(), Ok(first));
+    irq::free_vector(first);
+    irq::free_vector(second);
+}
+
+#[test_case]
+fn spurious_irq_7() {
+    CALLS.store(0, Ordering::Relaxed);
+    let id = irq::register_irq(7, "test", handled, 0).unwrap();
+    let start = irq::stats(0x27);
+    // Not in service at the PIC, so it looks like a spurious interrupt.
+    unsafe { asm!("int 0x27") };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+    assert_eq!(irq::stats(0x27).count, start.count + 1);
+    irq::unregister(id).unwrap();
+}
+
+#[test_case]
+fn unmask_isa_line() {
+    // IRQ 5 is usually unused.
+    let masked = || unsafe { PICS.lock().read_masks() }[0] & 1 << 5 != 0;
+    assert!(masked());
+    let id = irq::register_irq(5, "test", handled, 0).unwrap();
+    assert_eq!(id.vector(), 0x25);
+    assert!(!masked());
+    irq::unregister(id).unwrap();
+    assert!(masked());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::sync::IrqSpinlock;
+use osh1mc::timer;
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    osh1mc::init();
+    test_main();
+    loop {}
+}
+
+static LOCK: IrqSpinlock<u32> = IrqSpinlock::new("LOCK", 0);
+static OTHER: IrqSpinlock<u32> = IrqSpinlock::new("OTHER", 0);
+
+#[test_case]
+fn disables_interrupts_while_held() {
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = LOCK.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*LOCK.lock(), 1);
+}
+
+#[test_case]
+fn keeps_interrupts_disabled() {
+    interrupts::disable();
+    drop(LOCK.lock());
+    assert!(!interrupts::are_enabled());
+    interrupts::enable();
+}
+
+#[test_case]
+fn nested_locks() {
+    let outer = LOCK.lock();
+    {
+        let _other = OTHER.lock();
+        assert!(!interrupts::are_enabled());
+    }
+    // The inner guard saw interrupts disabled, so it leaves them that way.
+    assert!(!interrupts::are_enabled());
+    drop(outer);
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn try_lock_when_held() {
+    let guard = LOCK.lock();
+    assert!(LOCK.is_locked());
+    assert!(LOCK.try_lock().is_none());
+    // A failed try_lock must not turn interrupts back on.
+    assert!(!interrupts::are_enabled());
+    drop(guard);
+    assert!(LOCK.try_lock().is_some());
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn timer_ticks_while_printing() {
+    let start = timer::ticks();
+    for _ in 0..100 {
+        osh1mc::serial_print!("");
+        osh1mc::print!("");
+    }
+    for _ in 0..3 {
+        x86_64::instructions::hlt();
+    }
+    assert!(timer::ticks() > start);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
@@ -0,0 +1,113 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use osh1mc::memory::mmio::{CachePolicy, Mmio, MmioRegion};
+use osh1mc::memory::{self, bitmap::BitmapFrameAllocator};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use osh1mc::allocator;
+
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::install(mapper, frame_allocator);
+    test_main();
+    loop {}
+}
+
+// The VGA text buffer is device memory that is always there.
+const VGA_BUFFER: u64 = 0xb8000;
+
+fn translate(addr: VirtAddr) -> Option<memory::Translation> {
+    unsafe { memory::translate(addr, memory::physical_memory_offset()) }
+}
+
+#[test_case]
+fn map_uncached() {
+    let region = MmioRegion::map(
+        "vga text",
+        PhysAddr::new(VGA_BUFFER),
+        4000,
+        CachePolicy::Uncached,
+    )
+    .unwrap();
+    let translation = translate(region.virt_addr()).unwrap();
+    assert_eq!(translation.addr, PhysAddr::new(VGA_BUFFER));
+    assert!(translation.flags.contains(PageTableFlags::NO_CACHE));
+    region.write::<u16>(0, 0x0f41);
+    assert_eq!(region.read::<u16>(0), 0x0f41);
+}
+
+#[test_case]
+fn map_unaligned() {
+    let region = MmioRegion::map(
+        "vga text",
+        PhysAddr::new(VGA_BUFFER + 0x10),
+        0x1000,
+        CachePolicy::WriteCombining,
+    )
+    .unwrap();
+    assert_eq!(region.virt_addr().as_u64() & 0xfff, 0x10);
+    let translation = translate(region.virt_addr()).unwrap();
+    assert_eq!(translation.addr, PhysAddr::new(VGA_BUFFER + 0x10));
+    assert!(translation
+        .flags
+        .contains(CachePolicy::WriteCombining.flags()));
+    assert!(!translation.flags.contains(PageTableFlags::HUGE_PAGE));
+}
+
+#[test_case]
+fn unmapped_on_drop() {
+    let virt = {
+        let region = MmioRegion::map(
+            "vga text",
+            PhysAddr::new(VGA_BUFFER),
+            4000,
+            CachePolicy::Uncached,
+        )
+        .unwrap();
+        region.virt_addr()
+    };
+    assert!(translate(virt).is_none());
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Cell {
+    character: u8,
+    color: u8,
+}
+
+#[test_case]
+fn typed_access() {
+    let cell =
+        Mmio::<Cell>::map("vga cell", PhysAddr::new(VGA_BUFFER), CachePolicy::Uncached).unwrap();
+    cell.write(Cell {
+        character: b'x',
+        color: 0x0f,
+    });
+    assert_eq!(cell.read().character, b'x');
+    cell.write_field(|c| unsafe { addr_of_mut!((*c).color) }, 0x1f);
+    assert_eq!(
+        cell.read_field(|c| unsafe { addr_of_mut!((*c).color) }),
+        0x1f
+    );
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
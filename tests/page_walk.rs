@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use osh1mc::memory::{self, walk};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    osh1mc::init();
+    PHYSICAL_MEMORY_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    test_main();
+    loop {}
+}
+
+fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+#[test_case]
+fn translate_physical_memory_mapping() {
+    let offset = physical_memory_offset();
+    for &addr in &[0x1000, 0xb8000, 0x20_1234, 0x40_0000] {
+        let translation = unsafe { memory::translate(offset + addr, offset) }
+            .expect("physical memory is not mapped");
+        assert_eq!(translation.addr, PhysAddr::new(addr));
+        assert!(translation.flags.contains(PageTableFlags::PRESENT));
+    }
+}
+
+#[test_case]
+fn translate_unmapped_address() {
+    let offset = physical_memory_offset();
+    let addr = VirtAddr::new(0xdead_beef_0000);
+    assert_eq!(unsafe { memory::translate_addr(addr, offset) }, None);
+}
+
+#[test_case]
+fn walk_agrees_with_translate() {
+    let offset = physical_memory_offset();
+    let mut mappings = 0;
+    unsafe {
+        walk::walk(offset, |mapping| {
+            mappings += 1;
+            let translation = memory::translate(mapping.virt, offset).unwrap();
+            assert_eq!(translation.addr, mapping.phys);
+            assert_eq!(translation.size, mapping.size);
+            assert_eq!(translation.flags, mapping.flags);
+        })
+    };
+    assert!(mappings > 0);
+}
+
+#[test_case]
+fn ranges_cover_kernel_code() {
+    let offset = physical_memory_offset();
+    let code = VirtAddr::new(ranges_cover_kernel_code as usize as u64);
+    let mut found = false;
+    unsafe {
+        walk::for_each_range(offset, |range| {
+            if range.contains(code) {
+                found = true;
+                assert!(!range.flags.contains(PageTableFlags::NO_EXECUTE));
+            }
+        })
+    };
+    assert!(found);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
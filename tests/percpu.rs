@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::{percpu, timer};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    osh1mc::init();
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn boot_cpu() {
+    assert_eq!(percpu::cpu_count(), 1);
+    assert_eq!(percpu::cpu_id(), 0);
+    let cpu = percpu::current();
+    assert_eq!(cpu.cpu_id(), 0);
+    assert!(core::ptr::eq(cpu, percpu::cpu(0).unwrap()));
+    assert!(percpu::cpu(1).is_none());
+}
+
+#[test_case]
+fn gs_base_points_to_current() {
+    assert_eq!(GsBase::read(), VirtAddr::from_ptr(percpu::current()));
+}
+
+#[test_case]
+fn current_task() {
+    let cpu = percpu::current();
+    assert_eq!(cpu.current_task(), 0);
+    cpu.set_current_task(42);
+    assert_eq!(percpu::current().current_task(), 42);
+    cpu.set_current_task(0);
+}
+
+#[test_case]
+fn scratch_stack() {
+    let cpu = percpu::current();
+    cpu.set_scratch_stack(0xdead_b000);
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[8]", out(reg) value, options(nostack, readonly));
+    }
+    assert_eq!(percpu::SCRATCH_STACK_OFFSET, 8);
+    assert_eq!(value, 0xdead_b000);
+    cpu.set_scratch_stack(0);
+}
+
+#[test_case]
+fn interrupt_depth() {
+    assert!(!percpu::in_interrupt());
+    {
+        let _outer = percpu::InterruptGuard::enter();
+        let _inner = percpu::InterruptGuard::enter();
+        assert_eq!(percpu::current().interrupt_depth(), 2);
+        assert!(percpu::in_interrupt());
+    }
+    assert_eq!(percpu::current().interrupt_depth(), 0);
+}
+
+#[test_case]
+fn ticks_are_counted() {
+    let cpu = percpu::current();
+    let (start, start_cpu) = (timer::ticks(), cpu.ticks());
+    for _ in 0..3 {
+        x86_64::instructions::hlt();
+    }
+    assert!(timer::ticks() > start);
+    assert!(cpu.ticks() > start_cpu);
+    assert!(cpu.ticks() <= timer::ticks());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
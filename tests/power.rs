@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::memory;
+use osh1mc::power;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn s5_found() {
+    let (slp_typ_a, slp_typ_b) = power::s5_sleep_types().unwrap();
+    // SLP_TYP is a three bit field.
+    assert!(slp_typ_a < 8);
+    assert!(slp_typ_b < 8);
+}
+
+#[test_case]
+fn reboot_on_panic_option() {
+    assert!(!power::reboot_on_panic());
+    power::set_reboot_on_panic(true);
+    assert!(power::reboot_on_panic());
+    power::set_reboot_on_panic(false);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use osh1mc::sync::IrqSpinlock;
+use osh1mc::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static LOCK: IrqSpinlock<u32> = IrqSpinlock::new("LOCK", 0);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("recursive_lock::lock_twice...\t");
+    let _guard = LOCK.lock();
+    let _again = LOCK.lock();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
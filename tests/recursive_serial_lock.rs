@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use osh1mc::serial::{self, SERIAL1};
+use osh1mc::{exit_qemu, serial_print, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("recursive_serial_lock::print_while_locked...\t");
+    let _guard = SERIAL1.lock();
+    serial_print!("[test did not panic]\n");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// SERIAL1 is still held, so this only gets through without its lock.
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial::force_print(format_args!("[ok]\n"));
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use osh1mc::memory;
+use osh1mc::rtc::{self, DateTime};
+use osh1mc::timer::Instant;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    rtc::init();
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn plausible_date() {
+    let now = rtc::read();
+    assert!(now.year >= 2020, "{}", now);
+    assert!((1..=12).contains(&now.month));
+    assert!((1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+#[test_case]
+fn unix_time() {
+    let date = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 37,
+        second: 42,
+    };
+    assert_eq!(date.to_unix(), 1_709_213_862);
+    assert_eq!(DateTime::from_unix(1_709_213_862), date);
+    assert_eq!(DateTime::from_unix(0).year, 1970);
+}
+
+#[test_case]
+fn wall_clock_follows_rtc() {
+    let wall = rtc::now().to_unix();
+    let rtc = rtc::read().to_unix();
+    assert!(wall + 1 >= rtc && wall <= rtc + 1, "{} {}", wall, rtc);
+}
+
+#[test_case]
+fn periodic_interrupt() {
+    // 1024 Hz.
+    rtc::enable_periodic(6);
+    let ticks = rtc::periodic_ticks();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(500) {
+        x86_64::instructions::hlt();
+    }
+    rtc::disable_periodic();
+    let ticks = rtc::periodic_ticks() - ticks;
+    assert!(ticks > 400 && ticks < 620, "{} ticks", ticks);
+}
+
+#[test_case]
+fn alarm() {
+    let at = DateTime::from_unix(rtc::read().to_unix() + 2);
+    rtc::set_alarm(at.hour, at.minute, at.second, None);
+    let start = Instant::now();
+    while !rtc::alarm_fired() && start.elapsed() < Duration::from_secs(4) {
+        x86_64::instructions::hlt();
+    }
+    rtc::clear_alarm();
+    assert!(rtc::alarm_fired());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
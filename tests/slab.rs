@@ -0,0 +1,122 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::Layout;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::allocator::slab::{self, ObjectCache, SlabCache};
+use osh1mc::memory::bitmap::BitmapFrameAllocator;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use osh1mc::allocator;
+    use osh1mc::memory;
+    use x86_64::VirtAddr;
+
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::install(mapper, frame_allocator);
+    test_main();
+    loop {}
+}
+
+static BYTES: SlabCache = SlabCache::new("bytes", Layout::new::<[u8; 48]>(), None);
+
+#[test_case]
+fn alloc_and_free() {
+    let a = BYTES.alloc().unwrap();
+    let b = BYTES.alloc().unwrap();
+    assert_ne!(a, b);
+    assert_eq!(a.as_ptr() as usize % 8, 0);
+    let stats = BYTES.stats();
+    assert_eq!(stats.in_use, 2);
+    assert_eq!(stats.slabs, 1);
+    assert_eq!(stats.free, stats.objects_per_slab - 2);
+    unsafe {
+        BYTES.free(a);
+        BYTES.free(b);
+    }
+    assert_eq!(BYTES.stats().in_use, 0);
+    assert_eq!(BYTES.stats().frees, 2);
+}
+
+#[test_case]
+fn freed_object_is_reused() {
+    let a = BYTES.alloc().unwrap();
+    unsafe { BYTES.free(a) };
+    let b = BYTES.alloc().unwrap();
+    assert_eq!(a, b);
+    unsafe { BYTES.free(b) };
+}
+
+#[test_case]
+fn cache_grows_by_slabs() {
+    let mut objects = [None; 200];
+    for object in objects.iter_mut() {
+        *object = BYTES.alloc();
+    }
+    assert!(BYTES.stats().slabs > 1);
+    for object in objects.iter() {
+        unsafe { BYTES.free(object.unwrap()) };
+    }
+    assert!(BYTES.shrink() > 0);
+    assert_eq!(BYTES.stats().slabs, 0);
+}
+
+fn fill(object: *mut u8) {
+    unsafe { object.cast::<u64>().write(0xdead_beef) };
+}
+
+static CONSTRUCTED: SlabCache = SlabCache::new("constructed", Layout::new::<u64>(), Some(fill));
+
+#[test_case]
+fn constructor_runs() {
+    let object = CONSTRUCTED.alloc().unwrap();
+    assert_eq!(unsafe { *object.cast::<u64>().as_ptr() }, 0xdead_beef);
+    unsafe { CONSTRUCTED.free(object) };
+    // Freeing must not overwrite the constructed state.
+    let again = CONSTRUCTED.alloc().unwrap();
+    assert_eq!(again, object);
+    assert_eq!(unsafe { *again.cast::<u64>().as_ptr() }, 0xdead_beef);
+    unsafe { CONSTRUCTED.free(again) };
+}
+
+struct Inode {
+    number: u64,
+    size: u64,
+}
+
+static INODES: ObjectCache<Inode> = ObjectCache::new("inode");
+
+#[test_case]
+fn object_cache() {
+    let mut inode = INODES.alloc(Inode { number: 1, size: 0 }).unwrap();
+    inode.size = 4096;
+    assert_eq!(inode.number, 1);
+    assert_eq!(inode.size, 4096);
+    assert_eq!(INODES.stats().in_use, 1);
+    drop(inode);
+    assert_eq!(INODES.stats().in_use, 0);
+    let mut names = 0;
+    slab::for_each_cache(|cache| {
+        if cache.name() == "inode" {
+            names += 1;
+        }
+    });
+    assert_eq!(names, 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
@@ -2,17 +2,31 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use lazy_static::lazy_static;
+use osh1mc::memory::{self, bitmap::BitmapFrameAllocator, stack, vmm};
 use osh1mc::{exit_qemu, serial_print, serial_println, QemuExitCode};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
 
 extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
+    use x86_64::registers::control::Cr2;
+
+    let owner = stack::guard_page_owner(Cr2::read());
+    let local = 0u8;
+    let running_on = vmm::region_containing(VirtAddr::from_ptr(&local)).map(|r| r.name);
+    if owner == Some("boot") && running_on == Some("double fault") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("overflowed: {:?}, handler stack: {:?}", owner, running_on);
+        exit_qemu(QemuExitCode::Failed);
+    }
     loop {}
 }
 
@@ -32,11 +46,21 @@ pub fn init_test_idt() {
     TEST_IDT.load();
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
     osh1mc::gdt::init();
     init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::install(mapper, frame_allocator);
+    osh1mc::gdt::init_stacks().expect("IST stack allocation failed.");
+    stack::register_current_stack("boot").expect("boot stack registration failed.");
+
     stack_overflow();
     panic!("Execution continued after stack overflow")
 }
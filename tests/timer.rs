@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use osh1mc::timer::{self, Instant};
+use x86_64::instructions::port::Port;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    osh1mc::init();
+    test_main();
+    loop {}
+}
+
+// The seconds register of the CMOS real time clock, in whatever format it uses.
+fn cmos_seconds() -> u8 {
+    let mut index: Port<u8> = Port::new(0x70);
+    let mut data: Port<u8> = Port::new(0x71);
+    unsafe {
+        loop {
+            // Skip reads while an update is in progress.
+            index.write(0x0a);
+            if data.read() & 0x80 == 0 {
+                break;
+            }
+        }
+        index.write(0x00);
+        data.read()
+    }
+}
+
+fn wait_for_next_second() {
+    let start = cmos_seconds();
+    while cmos_seconds() == start {
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn tick_rate_matches_rtc() {
+    wait_for_next_second();
+    let start = Instant::now();
+    wait_for_next_second();
+    wait_for_next_second();
+    let elapsed = start.elapsed();
+    assert!(elapsed > Duration::from_millis(1900), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(2100), "{:?}", elapsed);
+}
+
+#[test_case]
+fn uptime_is_monotonic() {
+    let mut last = timer::uptime();
+    for _ in 0..1000 {
+        let now = timer::uptime();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn delay_waits() {
+    let start = Instant::now();
+    timer::delay(Duration::from_millis(100));
+    let elapsed = start.elapsed();
+    // The clock only advances every tick.
+    let tick = Duration::from_nanos(1_000_000_000 / u64::from(timer::frequency()));
+    assert!(
+        elapsed + tick >= Duration::from_millis(100),
+        "{:?}",
+        elapsed
+    );
+    assert!(elapsed < Duration::from_millis(200), "{:?}", elapsed);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
+
+#[test_case]
+fn init_rejects_zero() {
+    assert_eq!(timer::init(0), Err(timer::TimerError::ZeroFrequency));
+    assert_eq!(timer::frequency(), timer::DEFAULT_FREQUENCY);
+}
+
+#[test_case]
+fn init_stores_actual_frequency() {
+    // Below PIT_FREQUENCY / 0xffff the divisor is clamped.
+    timer::init(10).unwrap();
+    assert_eq!(timer::frequency(), timer::PIT_FREQUENCY / 0xffff);
+    timer::init(timer::DEFAULT_FREQUENCY).unwrap();
+    assert_eq!(timer::frequency(), timer::DEFAULT_FREQUENCY);
+}
@@ -0,0 +1,150 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use core::time::Duration;
+use osh1mc::timer::{self, wheel, Instant, Timeout};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    osh1mc::init();
+    test_main();
+    loop {}
+}
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn count(data: usize) {
+    CALLS.fetch_add(data, Ordering::Relaxed);
+}
+
+#[test_case]
+fn callback_fires() {
+    CALLS.store(0, Ordering::Relaxed);
+    let id = wheel::schedule(Duration::from_millis(50), count, 3).unwrap();
+    assert!(wheel::is_pending(id));
+    timer::sleep(Duration::from_millis(20));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+    timer::sleep(Duration::from_millis(60));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+    assert!(!wheel::is_pending(id));
+}
+
+#[test_case]
+fn cancel() {
+    CALLS.store(0, Ordering::Relaxed);
+    let id = wheel::schedule(Duration::from_millis(20), count, 1).unwrap();
+    assert!(wheel::cancel(id));
+    timer::sleep(Duration::from_millis(50));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+    assert!(!wheel::cancel(id));
+}
+
+#[test_case]
+fn many_timers() {
+    CALLS.store(0, Ordering::Relaxed);
+    // More than a turn of the wheel, so some share a slot with later rounds.
+    for i in 0..100 {
+        wheel::schedule(Duration::from_millis(i * 40), count, 1).unwrap();
+    }
+    timer::sleep(Duration::from_millis(99 * 40 + 50));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 100);
+}
+
+#[test_case]
+fn sleep_duration() {
+    let start = Instant::now();
+    timer::sleep(Duration::from_millis(100));
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert!(start.elapsed() < Duration::from_millis(150));
+}
+
+#[test_case]
+fn timeout() {
+    let timeout = Timeout::new(Duration::from_millis(30));
+    assert!(!timeout.expired());
+    assert_eq!(timeout.poll_until(|| None::<()>), None);
+    assert!(timeout.expired());
+    assert_eq!(
+        Timeout::new(Duration::from_secs(1)).poll_until(|| Some(1)),
+        Some(1)
+    );
+}
+
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+fn flag_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WOKEN.store(true, Ordering::Relaxed);
+    }
+    fn drop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+    unsafe { Waker::from_raw(clone(core::ptr::null())) }
+}
+
+#[test_case]
+fn sleep_future() {
+    let waker = flag_waker();
+    let mut context = Context::from_waker(&waker);
+    let start = Instant::now();
+    let mut sleep = timer::sleep_async(Duration::from_millis(50));
+    loop {
+        WOKEN.store(false, Ordering::Relaxed);
+        if let Poll::Ready(()) = Pin::new(&mut sleep).poll(&mut context) {
+            break;
+        }
+        // Only poll again once woken.
+        while !WOKEN.load(Ordering::Relaxed) {
+            x86_64::instructions::hlt();
+        }
+    }
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
+
+static RELEASED: AtomicUsize = AtomicUsize::new(0);
+
+// Counts how often a waker is consumed, by waking it or dropping it.
+fn counting_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn release(_: *const ()) {
+        RELEASED.fetch_add(1, Ordering::Relaxed);
+    }
+    fn wake_by_ref(_: *const ()) {
+        WOKEN.store(true, Ordering::Relaxed);
+    }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, release, wake_by_ref, release);
+    unsafe { Waker::from_raw(clone(core::ptr::null())) }
+}
+
+#[test_case]
+fn fired_waker_released() {
+    RELEASED.store(0, Ordering::Relaxed);
+    WOKEN.store(false, Ordering::Relaxed);
+    let id = wheel::schedule_wake(Duration::from_millis(10), counting_waker()).unwrap();
+    timer::sleep(Duration::from_millis(50));
+    assert!(!wheel::is_pending(id));
+    assert!(WOKEN.load(Ordering::Relaxed));
+    // The interrupt handler only wakes it, the drop waits for interrupts to be enabled.
+    assert_eq!(RELEASED.load(Ordering::Relaxed), 0);
+    assert!(!wheel::cancel(id));
+    assert_eq!(RELEASED.load(Ordering::Relaxed), 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
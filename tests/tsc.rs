@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use osh1mc::timer::{self, tsc, Instant};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    osh1mc::init();
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn calibrated() {
+    let frequency = tsc::frequency().unwrap();
+    assert!(frequency > 100_000_000, "{} Hz", frequency);
+}
+
+#[test_case]
+fn only_invariant() {
+    assert_eq!(tsc::nanos().is_some(), tsc::invariant());
+}
+
+#[test_case]
+fn monotonic() {
+    if !tsc::invariant() {
+        return;
+    }
+    let mut last = tsc::nanos().unwrap();
+    for _ in 0..1000 {
+        let now = tsc::nanos().unwrap();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn matches_pit() {
+    if !tsc::invariant() {
+        return;
+    }
+    let start = timer::timestamp();
+    timer::delay(Duration::from_millis(100));
+    let elapsed = timer::timestamp() - start;
+    assert!(elapsed > Duration::from_millis(90), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(110), "{:?}", elapsed);
+}
+
+#[test_case]
+fn matches_tick_clock() {
+    if !tsc::invariant() {
+        return;
+    }
+    let start = (Instant::now(), timer::timestamp());
+    while start.0.elapsed() < Duration::from_millis(500) {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = (start.0.elapsed(), timer::timestamp() - start.1);
+    let tick = Duration::from_nanos(1_000_000_000 / u64::from(timer::frequency()));
+    assert!(elapsed.1 + tick * 2 > elapsed.0, "{:?}", elapsed);
+    assert!(elapsed.1 < elapsed.0 + tick * 2, "{:?}", elapsed);
+}
+
+#[test_case]
+fn new_frequency_continues() {
+    if !tsc::invariant() {
+        return;
+    }
+    let frequency = tsc::frequency().unwrap();
+    let before = tsc::nanos().unwrap();
+    tsc::set_frequency(frequency * 2);
+    let after = tsc::nanos().unwrap();
+    tsc::set_frequency(frequency);
+    assert!(after >= before);
+    assert!(after - before < 1_000_000, "{} ns", after - before);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(osh1mc::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use osh1mc::allocator::{HEAP_MAX_SIZE, HEAP_START};
+use osh1mc::memory::fault;
+use osh1mc::memory::vmm::{self, Backing, VmError};
+use osh1mc::memory::{self, bitmap::BitmapFrameAllocator};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use osh1mc::allocator;
+
+    osh1mc::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed.");
+    memory::install(mapper, frame_allocator);
+    test_main();
+    loop {}
+}
+
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    unsafe { memory::translate_addr(addr, memory::physical_memory_offset()) }
+}
+
+#[test_case]
+fn heap_is_reserved() {
+    let heap = vmm::region_containing(VirtAddr::new(HEAP_START as u64)).unwrap();
+    assert_eq!(heap.name, "heap");
+    assert_eq!(heap.size, HEAP_MAX_SIZE as u64);
+    assert_eq!(
+        vmm::reserve_at("other", VirtAddr::new(HEAP_START as u64), 4096),
+        Err(VmError::Overlapping)
+    );
+}
+
+#[test_case]
+fn allocate_and_free() {
+    let flags = PageTableFlags::WRITABLE;
+    let region = vmm::allocate("buffer", 4 * 4096, flags).unwrap();
+    assert_eq!(region.backing, Backing::Allocated);
+    assert!(region.start.as_u64() >= vmm::KERNEL_SPACE_START);
+    let ptr: *mut u64 = (region.start + 3 * 4096u64).as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(7);
+        assert_eq!(ptr.read_volatile(), 7);
+    }
+    assert_eq!(vmm::region_containing(region.start + 4095u64), Some(region));
+    vmm::free(&region).unwrap();
+    assert_eq!(translate(region.start), None);
+    assert_eq!(vmm::region_containing(region.start), None);
+}
+
+#[test_case]
+fn regions_do_not_overlap() {
+    let a = vmm::reserve("a", 3 * 4096, 4096).unwrap();
+    let b = vmm::reserve("b", 4096, 0x10_0000).unwrap();
+    assert!(a.end() <= b.start || b.end() <= a.start);
+    assert_eq!(b.start.as_u64() % 0x10_0000, 0);
+    vmm::release(&a).unwrap();
+    vmm::release(&b).unwrap();
+}
+
+#[test_case]
+fn map_physical_memory() {
+    let flags = PageTableFlags::WRITABLE;
+    let region = vmm::map_phys("vga", PhysAddr::new(0xb8000), 4096, flags).unwrap();
+    assert_eq!(translate(region.start), Some(PhysAddr::new(0xb8000)));
+    let direct = memory::phys_to_virt(PhysAddr::new(0xb8000));
+    unsafe {
+        let ptr: *mut u16 = region.start.as_mut_ptr();
+        ptr.write_volatile(0x0f41);
+        assert_eq!(direct.as_ptr::<u16>().read_volatile(), 0x0f41);
+    }
+    vmm::free(&region).unwrap();
+    // Device memory is not handed to the frame allocator.
+    assert_eq!(translate(direct), Some(PhysAddr::new(0xb8000)));
+}
+
+#[test_case]
+fn lazy_region() {
+    let region = vmm::reserve("lazy", 8 * 4096, 4096).unwrap();
+    let region = vmm::map_lazy(&region, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(translate(region.start), None);
+    unsafe { region.start.as_mut_ptr::<u8>().write_volatile(1) };
+    assert!(translate(region.start).is_some());
+    vmm::free(&region).unwrap();
+    assert_eq!(translate(region.start), None);
+}
+
+#[test_case]
+fn lazy_region_guard() {
+    let region = vmm::reserve_with_guard("lazy", 4 * 4096, 4096).unwrap();
+    let region = vmm::map_lazy(&region, PageTableFlags::WRITABLE).unwrap();
+    assert!(fault::region_containing(region.start).is_none());
+    assert!(fault::region_containing(region.start + 4096u64).is_some());
+    vmm::free(&region).unwrap();
+    assert!(fault::region_containing(region.start + 4096u64).is_none());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info);
+}
@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use osh1mc::memory::protect::{self, Violation};
+use osh1mc::memory::{self, bitmap::BitmapFrameAllocator};
+use osh1mc::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    let violation = protect::classify(Cr2::read(), error_code);
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && violation == Violation::ReadOnly
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("error code: {:?}, violation: {:?}", error_code, violation);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("write_text::write_text...\t");
+    osh1mc::gdt::init();
+    TEST_IDT.load();
+
+    protect::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::install(mapper, frame_allocator);
+    protect::protect_kernel_image(&boot_info.memory_map).expect("kernel image protection failed.");
+
+    let text = main as *mut u8;
+    unsafe { text.write_volatile(0xcc) };
+    panic!("Execution continued after writing to .text")
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    osh1mc::test_panic_handler(info)
+}